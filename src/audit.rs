@@ -0,0 +1,81 @@
+//! A long-term log of which device won each conflict, across all runs, e.g. to spot a device whose clock is off.
+//!
+//! Each line is tab separated: when it was resolved, the task's UUID, the device whose snapshot won, and that
+//! snapshot's modified time. The file is rotated by size, so it never grows without bound.
+
+use crate::history::Merged;
+use color_eyre::{eyre::WrapErr, Result};
+use jiff::Timestamp;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Size above which the log is rotated before appending to it
+const MAX_SIZE: u64 = 1024 * 1024;
+
+/// Rotated logs kept next to the current one, as `<name>.1` (the newest) up to `<name>.3`
+const ROTATED_FILES: usize = 3;
+
+/// Append a line for each task in `merged` whose snapshots disagreed to the log at `path`
+pub fn append(path: &Path, merged: &[Merged], now: Timestamp) -> Result<()> {
+    let mut lines = String::new();
+    for m in merged.iter().filter(|m| m.conflicted) {
+        let modified = m.winner.task.modified().unwrap_or(m.winner.task.entry());
+        lines.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            now,
+            m.task.uuid(),
+            m.winner.source.device(),
+            modified.format("%Y-%m-%dT%H:%M:%SZ")
+        ));
+    }
+    if lines.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Unable to create {}", parent.display()))?;
+    }
+    rotate(path)?;
+    debug!("Appending to audit log {}", path.display());
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .wrap_err_with(|| format!("Unable to open audit log {}", path.display()))?;
+    file.write_all(lines.as_bytes())
+        .wrap_err_with(|| format!("Unable to write to audit log {}", path.display()))
+}
+
+/// Move the log at `path` aside once it is over `MAX_SIZE`, dropping the oldest rotated log
+fn rotate(path: &Path) -> Result<()> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.len() > MAX_SIZE => {}
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).wrap_err_with(|| format!("Unable to read {}", path.display())),
+    }
+
+    debug!("Rotating audit log {}", path.display());
+    for i in (1..ROTATED_FILES).rev() {
+        let from = rotated(path, i);
+        if from.exists() {
+            let to = rotated(path, i + 1);
+            fs::rename(&from, &to).wrap_err_with(|| {
+                format!("Unable to move {} to {}", from.display(), to.display())
+            })?;
+        }
+    }
+    let to = rotated(path, 1);
+    fs::rename(path, &to)
+        .wrap_err_with(|| format!("Unable to move {} to {}", path.display(), to.display()))
+}
+
+/// The path of the `n`th rotated log, e.g. `audit.log.1`
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
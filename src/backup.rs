@@ -0,0 +1,693 @@
+//! Backups of the databases in the state dir, and the backup dirs themselves.
+//!
+//! With `compress_backups`, a backup is a single `.tar.gz` archive rather than loose copies. Only ustar headers for
+//! regular files with plain names are written and read, and reading refuses everything else, so no entry can ever be
+//! restored outside the task dir.
+
+// TODO: write `.tar.zst` archives with the tar and zstd crates once they can be added as dependencies, still reading
+// the `.tar.gz` ones already made
+
+use crate::config::Config;
+use crate::fsutil;
+use crate::lock::Lock;
+use crate::prompt;
+use crate::sha256;
+use crate::target::Target;
+use color_eyre::{eyre::bail, eyre::WrapErr, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use jiff::fmt::strtime;
+use jiff::tz::TimeZone;
+use jiff::{civil::DateTime, Span, Timestamp, ToSpan, Zoned};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+/// Extension of the compressed archives in a backup dir
+const ARCHIVE_EXTENSION: &str = ".tar.gz";
+
+/// tar archives are made of blocks of this size
+const BLOCK: usize = 512;
+
+/// A backup dir in the state dir, which the databases are saved to before they are replaced or removed
+#[derive(Debug, Clone)]
+pub struct Backup {
+    pub dir: PathBuf,
+
+    /// Write the files into a single compressed archive in `dir` instead of copying them as-is
+    pub compress: bool,
+}
+
+impl Backup {
+    /// Save `files` to the backup dir. Compressed, they go into `<name>.tar.gz`. Returns the SHA-256 of each file by
+    /// name, for `verify` to check the backup against before it is restored
+    pub fn save(&self, name: &str, files: &[&Path]) -> Result<BTreeMap<String, String>> {
+        fs::create_dir_all(&self.dir)?;
+        let mut checksums = BTreeMap::new();
+        for path in files {
+            let checksum = sha256::digest_file(path)
+                .wrap_err_with(|| format!("Unable to read {}", path.display()))?;
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            checksums.insert(name, checksum);
+        }
+
+        if !self.compress {
+            for path in files {
+                let dest = self.dir.join(path.file_name().unwrap());
+                debug!("Backing up {}", dest.display());
+                fs::copy(path, &dest)?;
+            }
+            return Ok(checksums);
+        }
+
+        let archive = self.dir.join(format!("{}{}", name, ARCHIVE_EXTENSION));
+        debug!("Backing up {} files to {}", files.len(), archive.display());
+        write_archive(&archive, files)
+            .wrap_err_with(|| format!("Unable to write backup archive {}", archive.display()))?;
+        Ok(checksums)
+    }
+}
+
+/// Call `visit` with the name, backup time, and contents of every file backed up in `dir`, whether it was copied
+/// as-is or into an archive
+pub fn read_files(
+    dir: &Path,
+    mut visit: impl FnMut(&str, SystemTime, &mut dyn Read) -> Result<()>,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            debug!("Ignoring {}, its name is not valid UTF-8", path.display());
+            continue;
+        };
+        let modified = entry.metadata()?.modified()?;
+
+        if name.ends_with(ARCHIVE_EXTENSION) {
+            // Archive entries keep the mtime of the original file, but what matters here is when the backup was made
+            read_archive(&path, |name, contents| visit(name, modified, contents))
+                .wrap_err_with(|| format!("Unable to read backup archive {}", path.display()))?;
+        } else {
+            visit(&name, modified, &mut File::open(&path)?)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the files backed up in `dir` for which `include` is true against `checksums`, the ones recorded when they were
+/// backed up, failing on the first that doesn't match
+pub fn verify(
+    dir: &Path,
+    checksums: &BTreeMap<String, String>,
+    mut include: impl FnMut(&str) -> bool,
+) -> Result<()> {
+    read_files(dir, |name, _, contents| {
+        if !include(name) {
+            return Ok(());
+        }
+        let actual = sha256::of_reader(contents)
+            .wrap_err_with(|| format!("Unable to read {} from {}", name, dir.display()))?;
+        check(name, &actual, checksums)
+    })
+}
+
+/// Check the SHA-256 of the backed up file `name` against the one recorded for it. Backups made before checksums were
+/// recorded have none, which is only warned about
+pub fn check(name: &str, actual: &str, checksums: &BTreeMap<String, String>) -> Result<()> {
+    match checksums.get(name) {
+        Some(expected) if expected == actual => Ok(()),
+        Some(expected) => bail!(
+            "The backup of {} is corrupted, its SHA-256 is {} but was {} when it was backed up",
+            name,
+            actual,
+            expected
+        ),
+        None => {
+            warn!(
+                "No checksum was recorded for {}, restoring it unverified",
+                name
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Name of the backup dir for a run at `now`. The UTC offset is appended so names from different time zones can
+/// always be told apart, and ordered
+pub fn backup_name(now: &Zoned, date_format: &str) -> String {
+    format!("{}{}", now.strftime(date_format), now.strftime("%z"))
+}
+
+/// When the backup dir `name` was made. Names from before the UTC offset was appended are in UTC
+pub fn parse_backup_name(name: &str, date_format: &str) -> Option<Timestamp> {
+    if let Ok(timestamp) =
+        strtime::parse(format!("{}%z", date_format), name).and_then(|time| time.to_timestamp())
+    {
+        return Some(timestamp);
+    }
+    let datetime = DateTime::strptime(date_format, name).ok()?;
+    Some(datetime.to_zoned(TimeZone::UTC).ok()?.timestamp())
+}
+
+/// The backup directories in the state dir, oldest first
+pub fn list(state_dir: &Path, date_format: &str) -> Result<Vec<(Timestamp, PathBuf)>> {
+    let mut entries: Vec<(Timestamp, PathBuf)> = Vec::new();
+    if !state_dir.is_dir() {
+        // Nothing has been backed up yet
+        return Ok(entries);
+    }
+
+    for entry in fs::read_dir(state_dir)? {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                warn!(
+                    "Ignoring unreadable entry in {}: {}",
+                    state_dir.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        let timestamp = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| parse_backup_name(name, date_format));
+        match timestamp {
+            Some(timestamp) if path.is_dir() => entries.push((timestamp, path)),
+            _ => debug!("Ignoring {} in state dir, not a backup", path.display()),
+        }
+    }
+
+    // Sort by timestamp
+    entries.sort_by_key(|x| x.0);
+    Ok(entries)
+}
+
+/// Remove the oldest backups from the state dir if we have too many entries
+pub fn cleanup_state_dir(state_dir: &Path, config: &Config, num_to_keep: usize) -> Result<()> {
+    let entries = list(state_dir, config.backup_date_format())?;
+
+    // Backups older than this are removed regardless of how many there are
+    let cutoff = match config.keep_days {
+        Some(days) => {
+            let days = i64::try_from(days)?;
+            let now = Zoned::now().with_time_zone(TimeZone::UTC);
+            Some(now.checked_sub(days.days())?.timestamp())
+        }
+        None => None,
+    };
+
+    // A backup is only kept if it is both one of the newest num_to_keep entries and newer than the cutoff. Only
+    // entries that parsed as backups count towards num_to_keep, so stray files never push out a real backup
+    let excess = entries.len().saturating_sub(num_to_keep);
+    for (i, (timestamp, path)) in entries.iter().enumerate() {
+        let too_many = i < excess;
+        let too_old = cutoff.is_some_and(|cutoff| *timestamp < cutoff);
+        if too_many || too_old {
+            debug!("Removing old backup {}", path.display());
+            // Pruning is housekeeping; a backup that can't be removed now will be retried on the next run
+            if let Err(e) = fs::remove_dir_all(path) {
+                warn!("Unable to remove old backup {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove every backup of `targets`, or only those older than `older_than`, after asking for confirmation
+pub fn purge(
+    targets: &[Target],
+    date_format: &str,
+    older_than: Option<Span>,
+    yes: bool,
+) -> Result<()> {
+    let cutoff = match older_than {
+        Some(span) => Some(
+            Zoned::now()
+                .checked_sub(span)
+                .wrap_err_with(|| format!("Unable to go back {} from now", span))?
+                .timestamp(),
+        ),
+        None => None,
+    };
+
+    let mut to_remove = Vec::new();
+    for target in targets {
+        for (timestamp, path) in list(&target.state_dir, date_format)? {
+            if cutoff.is_none_or(|cutoff| timestamp < cutoff) {
+                to_remove.push(path);
+            }
+        }
+    }
+    if to_remove.is_empty() {
+        eprintln!("No backups to remove");
+        return Ok(());
+    }
+
+    if !yes {
+        if !io::stdin().is_terminal() {
+            bail!("Refusing to remove backups without confirmation, pass --yes");
+        }
+        let question = format!("Remove {} backups?", to_remove.len());
+        if !prompt::confirm(&question)? {
+            eprintln!("Aborted, nothing was removed");
+            return Ok(());
+        }
+    }
+
+    // Held per state dir while its backups are removed, so a resolve running meanwhile can't be handed a half
+    // removed backup
+    let mut freed = 0;
+    let mut removed = 0;
+    for target in targets {
+        let paths: Vec<&PathBuf> = to_remove
+            .iter()
+            .filter(|path| path.parent() == Some(&target.state_dir))
+            .collect();
+        if paths.is_empty() {
+            continue;
+        }
+        let _lock = Lock::acquire(&target.state_dir)?;
+        for path in paths {
+            let size = fsutil::dir_size(path).unwrap_or(0);
+            debug!("Removing backup {}", path.display());
+            match fs::remove_dir_all(path) {
+                Ok(()) => {
+                    freed += size;
+                    removed += 1;
+                }
+                Err(e) => warn!("Unable to remove backup {}: {}", path.display(), e),
+            }
+        }
+    }
+    eprintln!(
+        "Removed {} backups, freeing {}",
+        removed,
+        fsutil::format_size(freed)
+    );
+
+    Ok(())
+}
+
+fn write_archive(archive: &Path, files: &[&Path]) -> Result<()> {
+    let mut out = GzEncoder::new(
+        BufWriter::new(File::create(archive)?),
+        Compression::default(),
+    );
+
+    for path in files {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            bail!("{} has no UTF-8 file name", path.display());
+        };
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let size = metadata.len();
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        out.write_all(&header(name, size, mtime)?)?;
+        let copied = io::copy(&mut file.take(size), &mut out)?;
+        if copied != size {
+            bail!(
+                "{} changed size while it was being backed up",
+                path.display()
+            );
+        }
+        out.write_all(&vec![0; padding(size)])?;
+    }
+
+    // The end of the archive is marked by two empty blocks
+    out.write_all(&[0; 2 * BLOCK])?;
+    out.finish()?.flush()?;
+    Ok(())
+}
+
+fn read_archive(
+    archive: &Path,
+    mut visit: impl FnMut(&str, &mut dyn Read) -> Result<()>,
+) -> Result<()> {
+    let mut input = GzDecoder::new(BufReader::new(File::open(archive)?));
+
+    loop {
+        let mut block = [0; BLOCK];
+        input.read_exact(&mut block)?;
+        if block.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+
+        let name = std::str::from_utf8(field(&block[..100]))?;
+        let size = parse_octal(&block[124..136])?;
+        if !matches!(block[156], b'0' | 0) {
+            bail!("Entry '{}' is not a regular file", name);
+        }
+        // Entries are restored by name, so they must never point outside the dir they are restored to
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            bail!("Entry '{}' is not a plain file name", name);
+        }
+
+        let mut contents = (&mut input).take(size);
+        visit(name, &mut contents)?;
+        // Skip whatever `visit` didn't read, so the next header is found
+        io::copy(&mut contents, &mut io::sink())?;
+        io::copy(
+            &mut (&mut input).take(padding(size) as u64),
+            &mut io::sink(),
+        )?;
+    }
+}
+
+/// A ustar header for a regular file
+fn header(name: &str, size: u64, mtime: u64) -> Result<[u8; BLOCK]> {
+    if name.len() >= 100 {
+        bail!("File name '{}' is too long for the backup archive", name);
+    }
+    if size >= 8u64.pow(11) {
+        bail!("'{}' is too large for the backup archive", name);
+    }
+
+    let mut block = [0; BLOCK];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut block[100..108], 0o644); // mode
+    write_octal(&mut block[108..116], 0); // uid
+    write_octal(&mut block[116..124], 0); // gid
+    write_octal(&mut block[124..136], size);
+    write_octal(&mut block[136..148], mtime);
+    block[156] = b'0'; // regular file
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    // The checksum is the sum of the header's bytes, counting the checksum field itself as spaces
+    block[148..156].fill(b' ');
+    let checksum = block.iter().map(|&b| u64::from(b)).sum();
+    write_octal(&mut block[148..155], checksum);
+
+    Ok(block)
+}
+
+/// Zeros needed to fill up the last block of a file of `size` bytes
+fn padding(size: u64) -> usize {
+    let rem = (size % BLOCK as u64) as usize;
+    (BLOCK - rem) % BLOCK
+}
+
+/// Zero padded octal digits, terminated by a NUL
+fn write_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(digits.as_bytes());
+    field[width] = 0;
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64> {
+    let digits = std::str::from_utf8(field)?.trim_matches(|c| c == ' ' || c == '\0');
+    u64::from_str_radix(digits, 8)
+        .wrap_err_with(|| format!("Invalid number '{}' in archive", digits))
+}
+
+/// The bytes of a NUL terminated header field
+fn field(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Back up a file named `name` holding `contents` from a scratch dir into `dir`
+    fn save(dir: &Path, compress: bool, name: &str, contents: &[u8]) -> BTreeMap<String, String> {
+        let src = tempfile::tempdir().unwrap();
+        let path = src.path().join(name);
+        fs::write(&path, contents).unwrap();
+        let backup = Backup {
+            dir: dir.to_owned(),
+            compress,
+        };
+        backup.save("databases", &[&path]).unwrap()
+    }
+
+    #[test]
+    fn intact_backup_is_verified() {
+        for compress in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let checksums = save(dir.path(), compress, "taskchampion.sqlite3", b"tasks");
+            assert_eq!(
+                checksums["taskchampion.sqlite3"],
+                sha256::of_reader(&mut &b"tasks"[..]).unwrap()
+            );
+            verify(dir.path(), &checksums, |_| true).unwrap();
+        }
+    }
+
+    #[test]
+    fn corrupted_backup_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let checksums = save(dir.path(), false, "taskchampion.sqlite3", b"tasks");
+        fs::write(dir.path().join("taskchampion.sqlite3"), b"tasKs").unwrap();
+        let err = verify(dir.path(), &checksums, |_| true).unwrap_err();
+        assert!(err.to_string().contains("is corrupted"), "{}", err);
+    }
+
+    #[test]
+    fn corrupted_archive_entry_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut checksums = save(dir.path(), true, "taskchampion.sqlite3", b"tasks");
+        // The same as a bit flipping in the archive after its checksum was recorded
+        checksums.insert(
+            "taskchampion.sqlite3".to_owned(),
+            sha256::of_reader(&mut &b"other"[..]).unwrap(),
+        );
+        assert!(verify(dir.path(), &checksums, |_| true).is_err());
+    }
+
+    #[test]
+    fn excluded_files_are_not_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let checksums = save(dir.path(), false, "report.toml", b"report");
+        fs::write(dir.path().join("report.toml"), b"changed").unwrap();
+        verify(dir.path(), &checksums, |name| name != "report.toml").unwrap();
+    }
+
+    #[test]
+    fn missing_checksum_is_only_warned_about() {
+        check("taskchampion.sqlite3", "abc", &BTreeMap::new()).unwrap();
+    }
+
+    /// The names of the entries left in `dir`, sorted
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn cleanup_leaves_stray_files_and_dirs_alone() {
+        let state_dir = tempfile::tempdir().unwrap();
+        for name in [
+            "2024-01-01_00-00-00+0000",
+            "2024-01-02_00-00-00+0000",
+            "2024-01-03_00-00-00+0000",
+            "half-written",
+        ] {
+            fs::create_dir(state_dir.path().join(name)).unwrap();
+        }
+        // Named like a backup, but not a dir
+        for name in [".DS_Store", "README", "2023-12-31_00-00-00+0000"] {
+            fs::write(state_dir.path().join(name), b"").unwrap();
+        }
+
+        cleanup_state_dir(state_dir.path(), &Config::default(), 1).unwrap();
+        assert_eq!(
+            entries(state_dir.path()),
+            [
+                ".DS_Store",
+                "2023-12-31_00-00-00+0000",
+                "2024-01-03_00-00-00+0000",
+                "README",
+                "half-written",
+            ]
+        );
+    }
+
+    #[test]
+    fn backups_are_ordered_by_their_parsed_time() {
+        let state_dir = tempfile::tempdir().unwrap();
+        // Oldest first: 05:00 UTC, 05:30 UTC from before offsets were appended, and 06:00 UTC. By name the first is
+        // the last
+        for name in [
+            "2024-01-02_10-00-00+0500",
+            "2024-01-02_05-30-00",
+            "2024-01-02_06-00-00+0000",
+            "junk",
+        ] {
+            fs::create_dir(state_dir.path().join(name)).unwrap();
+        }
+
+        let date_format = Config::default().backup_date_format().to_owned();
+        let listed: Vec<PathBuf> = list(state_dir.path(), &date_format)
+            .unwrap()
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                state_dir.path().join("2024-01-02_10-00-00+0500"),
+                state_dir.path().join("2024-01-02_05-30-00"),
+                state_dir.path().join("2024-01-02_06-00-00+0000"),
+            ]
+        );
+
+        cleanup_state_dir(state_dir.path(), &Config::default(), 2).unwrap();
+        assert_eq!(
+            entries(state_dir.path()),
+            ["2024-01-02_05-30-00", "2024-01-02_06-00-00+0000", "junk"]
+        );
+    }
+
+    #[test]
+    fn backup_names_parse_back() {
+        let date_format = Config::default().backup_date_format().to_owned();
+        let now: Zoned = "2024-01-02T03:04:05+05:00[+05:00]".parse().unwrap();
+        let name = backup_name(&now, &date_format);
+        assert_eq!(name, "2024-01-02_03-04-05+0500");
+        assert_eq!(
+            parse_backup_name(&name, &date_format),
+            Some(now.timestamp())
+        );
+        assert_eq!(parse_backup_name("junk", &date_format), None);
+    }
+
+    #[test]
+    fn archive_round_trip() {
+        let src = tempfile::tempdir().unwrap();
+        // Block sized, and not, and empty
+        let files: Vec<(&str, Vec<u8>)> = vec![
+            (
+                "taskchampion.sqlite3",
+                (0..=255).cycle().take(3 * BLOCK).collect(),
+            ),
+            (
+                "taskchampion.sync-conflict-20240103-101010-ABCDEFG.sqlite3",
+                b"conflict".to_vec(),
+            ),
+            ("empty", Vec::new()),
+        ];
+        let paths: Vec<PathBuf> = files
+            .iter()
+            .map(|(name, contents)| {
+                let path = src.path().join(name);
+                fs::write(&path, contents).unwrap();
+                path
+            })
+            .collect();
+        let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let backup = Backup {
+            dir: dir.path().to_owned(),
+            compress: true,
+        };
+        let checksums = backup.save("databases", &paths).unwrap();
+        assert_eq!(entries(dir.path()), ["databases.tar.gz"]);
+
+        let mut read = Vec::new();
+        read_files(dir.path(), |name, _, contents| {
+            let mut buf = Vec::new();
+            contents.read_to_end(&mut buf)?;
+            read.push((name.to_owned(), buf));
+            Ok(())
+        })
+        .unwrap();
+        let expected: Vec<(String, Vec<u8>)> = files
+            .into_iter()
+            .map(|(name, contents)| (name.to_owned(), contents))
+            .collect();
+        assert_eq!(read, expected);
+        verify(dir.path(), &checksums, |_| true).unwrap();
+    }
+
+    /// Write an archive with an entry for each of `names` to `dir`, the way `write_archive` would if it let them
+    fn write_raw_archive(dir: &Path, names: &[&str]) {
+        let file = File::create(dir.join(format!("raw{}", ARCHIVE_EXTENSION))).unwrap();
+        let mut out = GzEncoder::new(file, Compression::default());
+        for name in names {
+            out.write_all(&header(name, 4, 0).unwrap()).unwrap();
+            out.write_all(b"evil").unwrap();
+            out.write_all(&vec![0; padding(4)]).unwrap();
+        }
+        out.write_all(&[0; 2 * BLOCK]).unwrap();
+        out.finish().unwrap();
+    }
+
+    #[test]
+    fn archive_entries_outside_the_dir_are_refused() {
+        for name in [
+            "/etc/passwd",
+            "../taskchampion.sqlite3",
+            "a/b",
+            "..",
+            ".",
+            "",
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            write_raw_archive(dir.path(), &["fine", name]);
+            let mut seen = Vec::new();
+            let err = read_files(dir.path(), |name, _, _| {
+                seen.push(name.to_owned());
+                Ok(())
+            })
+            .unwrap_err();
+            assert!(
+                format!("{:#}", err).contains("is not a plain file name"),
+                "{}: {:#}",
+                name,
+                err
+            );
+            // The entries before it are read, but never the bad one
+            assert_eq!(seen, ["fine"]);
+        }
+    }
+
+    #[test]
+    fn archive_entries_that_are_not_regular_files_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut block = header("link", 0, 0).unwrap();
+        block[156] = b'2';
+        let file = File::create(dir.path().join(format!("raw{}", ARCHIVE_EXTENSION))).unwrap();
+        let mut out = GzEncoder::new(file, Compression::default());
+        out.write_all(&block).unwrap();
+        out.write_all(&[0; 2 * BLOCK]).unwrap();
+        out.finish().unwrap();
+
+        let err = read_files(dir.path(), |_, _, _| Ok(())).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("is not a regular file"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn names_too_long_for_the_header_are_refused() {
+        assert!(header(&"a".repeat(99), 0, 0).is_ok());
+        assert!(header(&"a".repeat(100), 0, 0).is_err());
+    }
+}
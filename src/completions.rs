@@ -0,0 +1,411 @@
+//! Shell completion scripts, generated from the clap definition of the CLI.
+//! zsh reuses the bash script through `bashcompinit`.
+
+// TODO: generate these with clap_complete once it can be added as a dependency
+
+use clap::builder::{Arg, Command, ValueHint};
+use clap::ValueEnum;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// The completion script for `shell`
+pub fn generate(mut cmd: Command, shell: Shell) -> String {
+    cmd.build();
+    match shell {
+        Shell::Bash => bash(&cmd),
+        Shell::Zsh => format!(
+            "#compdef {name}\nautoload -U +X bashcompinit && bashcompinit\n{}",
+            bash(&cmd),
+            name = cmd.get_name()
+        ),
+        Shell::Fish => fish(&cmd),
+    }
+}
+
+/// Options (not positional arguments) that `cmd` accepts
+fn options(cmd: &Command) -> impl Iterator<Item = &Arg> {
+    cmd.get_arguments()
+        .filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+}
+
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_num_args().is_some_and(|n| n.takes_values())
+}
+
+/// Every spelling of `arg`, e.g. `-t --task-dir`
+fn spellings(arg: &Arg) -> Vec<String> {
+    let shorts = arg.get_short_and_visible_aliases().unwrap_or_default();
+    let longs = arg.get_long_and_visible_aliases().unwrap_or_default();
+    shorts
+        .into_iter()
+        .map(|s| format!("-{}", s))
+        .chain(longs.into_iter().map(|l| format!("--{}", l)))
+        .collect()
+}
+
+fn possible_values(arg: &Arg) -> Vec<String> {
+    arg.get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_owned())
+        .collect()
+}
+
+/// Possible values of the positional arguments of `cmd`, e.g. the shells for `completions`
+fn positional_values(cmd: &Command) -> impl Iterator<Item = String> + '_ {
+    cmd.get_positionals().flat_map(possible_values)
+}
+
+fn bash(cmd: &Command) -> String {
+    let name = cmd.get_name();
+    let func = format!("_{}", name.replace('-', "_"));
+    let subcommands: Vec<&Command> = cmd.get_subcommands().collect();
+    let mut out = String::new();
+
+    writeln!(out, "{}() {{", func).unwrap();
+    writeln!(out, "    local cur prev sub opts word").unwrap();
+    writeln!(out, "    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"").unwrap();
+    writeln!(out, "    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"").unwrap();
+
+    // Values of options, for every option of every (sub)command
+    writeln!(out, "    case \"$prev\" in").unwrap();
+    let mut seen = Vec::new();
+    for arg in std::iter::once(cmd)
+        .chain(subcommands.iter().copied())
+        .flat_map(options)
+        .filter(|arg| takes_value(arg))
+    {
+        let pattern = spellings(arg).join("|");
+        if seen.contains(&pattern) {
+            continue;
+        }
+        let values = possible_values(arg);
+        let reply = if !values.is_empty() {
+            format!("compgen -W \"{}\" -- \"$cur\"", values.join(" "))
+        } else {
+            match arg.get_value_hint() {
+                ValueHint::DirPath => "compgen -d -- \"$cur\"".to_owned(),
+                ValueHint::FilePath | ValueHint::AnyPath | ValueHint::ExecutablePath => {
+                    "compgen -f -- \"$cur\"".to_owned()
+                }
+                _ => String::new(),
+            }
+        };
+        let reply = match reply.is_empty() {
+            true => "COMPREPLY=()".to_owned(),
+            false => format!("COMPREPLY=($({}))", reply),
+        };
+        writeln!(out, "        {}) {}; return ;;", pattern, reply).unwrap();
+        seen.push(pattern);
+    }
+    writeln!(out, "    esac").unwrap();
+
+    // Which subcommand, if any, has been typed so far
+    let names: Vec<&str> = subcommands.iter().map(|sub| sub.get_name()).collect();
+    writeln!(out, "    sub=\"\"").unwrap();
+    writeln!(
+        out,
+        "    for word in \"${{COMP_WORDS[@]:1:COMP_CWORD-1}}\"; do"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "        case \"$word\" in {}) sub=\"$word\"; break ;; esac",
+        names.join("|")
+    )
+    .unwrap();
+    writeln!(out, "    done").unwrap();
+
+    writeln!(out, "    case \"$sub\" in").unwrap();
+    let top: Vec<String> = names
+        .iter()
+        .map(|name| name.to_string())
+        .chain(options(cmd).flat_map(spellings))
+        .collect();
+    writeln!(out, "        \"\") opts=\"{}\" ;;", top.join(" ")).unwrap();
+    for sub in &subcommands {
+        let opts: Vec<String> = positional_values(sub)
+            .chain(options(sub).flat_map(spellings))
+            .collect();
+        writeln!(
+            out,
+            "        {}) opts=\"{}\" ;;",
+            sub.get_name(),
+            opts.join(" ")
+        )
+        .unwrap();
+    }
+    writeln!(out, "    esac").unwrap();
+    writeln!(out, "    COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "complete -F {} {}", func, name).unwrap();
+    out
+}
+
+/// Quote `s` for fish's single quoted strings
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish_arg(out: &mut String, name: &str, condition: Option<&str>, arg: &Arg) {
+    let mut line = format!("complete -c {}", name);
+    if let Some(condition) = condition {
+        write!(line, " -n {}", fish_quote(condition)).unwrap();
+    }
+    if let Some(short) = arg.get_short() {
+        write!(line, " -s {}", short).unwrap();
+    }
+    if let Some(long) = arg.get_long() {
+        write!(line, " -l {}", long).unwrap();
+    }
+    if takes_value(arg) {
+        let values = possible_values(arg);
+        if !values.is_empty() {
+            write!(line, " -x -a {}", fish_quote(&values.join(" "))).unwrap();
+        } else {
+            match arg.get_value_hint() {
+                ValueHint::DirPath => {
+                    write!(line, " -x -a '(__fish_complete_directories)'").unwrap()
+                }
+                ValueHint::FilePath | ValueHint::AnyPath | ValueHint::ExecutablePath => {
+                    write!(line, " -r -F").unwrap()
+                }
+                _ => write!(line, " -x").unwrap(),
+            }
+        }
+    }
+    if let Some(help) = arg.get_help() {
+        let help = help.to_string();
+        let help = help.lines().next().unwrap_or_default();
+        write!(line, " -d {}", fish_quote(help)).unwrap();
+    }
+    writeln!(out, "{}", line).unwrap();
+}
+
+fn fish(cmd: &Command) -> String {
+    let name = cmd.get_name();
+    let mut out = String::new();
+    writeln!(out, "complete -c {} -f", name).unwrap();
+
+    for arg in options(cmd) {
+        // Global options are available everywhere, the flattened resolve options only before a subcommand
+        let condition = (!arg.is_global_set()).then_some("__fish_use_subcommand");
+        fish_arg(&mut out, name, condition, arg);
+    }
+    for sub in cmd.get_subcommands() {
+        let about = sub
+            .get_about()
+            .map(|about| about.to_string())
+            .unwrap_or_default();
+        writeln!(
+            out,
+            "complete -c {} -n '__fish_use_subcommand' -a {} -d {}",
+            name,
+            sub.get_name(),
+            fish_quote(&about)
+        )
+        .unwrap();
+        let condition = format!("__fish_seen_subcommand_from {}", sub.get_name());
+        let values: Vec<String> = positional_values(sub).collect();
+        if !values.is_empty() {
+            writeln!(
+                out,
+                "complete -c {} -n {} -a {}",
+                name,
+                fish_quote(&condition),
+                fish_quote(&values.join(" "))
+            )
+            .unwrap();
+        }
+        for arg in options(sub).filter(|arg| !arg.is_global_set()) {
+            fish_arg(&mut out, name, Some(&condition), arg);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cli;
+    use clap::CommandFactory;
+    use std::path::Path;
+    use std::process;
+
+    fn built() -> Command {
+        let mut cmd = Cli::command();
+        cmd.build();
+        cmd
+    }
+
+    #[test]
+    fn every_option_and_subcommand_is_completed() {
+        let cmd = built();
+        let bash = generate(Cli::command(), Shell::Bash);
+        let fish = generate(Cli::command(), Shell::Fish);
+        for sub in cmd.get_subcommands() {
+            assert!(
+                bash.contains(sub.get_name()),
+                "{} missing from bash",
+                sub.get_name()
+            );
+            assert!(
+                fish.contains(&format!("-a {}", sub.get_name())),
+                "{} missing from fish",
+                sub.get_name()
+            );
+            for arg in options(&cmd).chain(options(sub)) {
+                if let Some(long) = arg.get_long() {
+                    assert!(
+                        bash.contains(&format!("--{}", long)),
+                        "--{} missing from bash",
+                        long
+                    );
+                    assert!(
+                        fish.contains(&format!("-l {}", long)),
+                        "--{} missing from fish",
+                        long
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn each_subcommand_completes_its_own_options_and_values() {
+        let cmd = built();
+        let bash = generate(Cli::command(), Shell::Bash);
+        let fish = generate(Cli::command(), Shell::Fish);
+        for sub in cmd.get_subcommands() {
+            let arm = format!("{}) opts=\"", sub.get_name());
+            let line = bash
+                .lines()
+                .map(str::trim_start)
+                .find(|line| line.starts_with(&arm))
+                .unwrap_or_else(|| panic!("no bash case for {}", sub.get_name()));
+            let mut completed: Vec<&str> = line[arm.len()..]
+                .trim_end_matches("\" ;;")
+                .split_whitespace()
+                .collect();
+            let mut expected: Vec<String> = positional_values(sub)
+                .chain(options(sub).flat_map(spellings))
+                .collect();
+            completed.sort();
+            expected.sort();
+            assert_eq!(completed, expected, "bash options of {}", sub.get_name());
+
+            let condition = format!("-n '__fish_seen_subcommand_from {}'", sub.get_name());
+            for arg in options(sub).filter(|arg| !arg.is_global_set()) {
+                let Some(long) = arg.get_long() else { continue };
+                let line = fish
+                    .lines()
+                    .find(|line| {
+                        line.contains(&condition) && line.contains(&format!(" -l {} ", long))
+                    })
+                    .unwrap_or_else(|| {
+                        panic!("no fish completion of {} --{}", sub.get_name(), long)
+                    });
+                let values = possible_values(arg);
+                if !values.is_empty() {
+                    assert!(
+                        line.contains(&format!("-x -a '{}'", values.join(" "))),
+                        "{}",
+                        line
+                    );
+                }
+            }
+        }
+
+        // The values of every option, wherever it is
+        for arg in std::iter::once(&cmd)
+            .chain(cmd.get_subcommands())
+            .flat_map(options)
+        {
+            let values = possible_values(arg);
+            if values.is_empty() {
+                continue;
+            }
+            let line = format!(
+                "{}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;",
+                spellings(arg).join("|"),
+                values.join(" ")
+            );
+            assert!(bash.contains(&line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn task_dir_completes_directories() {
+        let bash = generate(Cli::command(), Shell::Bash);
+        assert!(bash.contains("-t|--task-dir) COMPREPLY=($(compgen -d -- \"$cur\")); return ;;"));
+        let fish = generate(Cli::command(), Shell::Fish);
+        assert!(fish
+            .lines()
+            .any(|line| line.contains("-s t -l task-dir -x -a '(__fish_complete_directories)'")));
+    }
+
+    #[test]
+    fn zsh_wraps_the_bash_script() {
+        let zsh = generate(Cli::command(), Shell::Zsh);
+        assert!(zsh.starts_with("#compdef syncthing-task-resolve\n"));
+        assert!(zsh.ends_with(&generate(Cli::command(), Shell::Bash)));
+    }
+
+    /// Complete the words typed so far (the last one being completed) with the bash script, run in `dir`
+    fn bash_complete(dir: &Path, words: &[&str]) -> Vec<String> {
+        let script = format!(
+            "{}\nCOMP_WORDS=({})\nCOMP_CWORD={}\n_syncthing_task_resolve\nprintf '%s\\n' \"${{COMPREPLY[@]}}\"",
+            generate(Cli::command(), Shell::Bash),
+            words.iter().map(|word| format!("'{}'", word)).collect::<Vec<_>>().join(" "),
+            words.len() - 1
+        );
+        let output = process::Command::new("bash")
+            .arg("-c")
+            .arg(script)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn bash_completes_subcommands_values_and_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("tasks")).unwrap();
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+        let complete = |words: &[&str]| bash_complete(dir.path(), words);
+
+        assert_eq!(
+            complete(&["syncthing-task-resolve", "complet"]),
+            ["completions"]
+        );
+        assert_eq!(
+            complete(&["syncthing-task-resolve", "completions", "f"]),
+            ["fish"]
+        );
+        assert_eq!(
+            complete(&["syncthing-task-resolve", "--task-dir", ""]),
+            ["tasks"]
+        );
+        assert_eq!(
+            complete(&["syncthing-task-resolve", "restore", "--task-d"]),
+            ["--task-dir"]
+        );
+    }
+}
@@ -1,23 +1,425 @@
-use std::path::PathBuf;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use color_eyre::{eyre::bail, eyre::eyre, eyre::Report, eyre::WrapErr, Result};
+use jiff::civil::{date, DateTime};
+use jiff::fmt::strtime;
+use jiff::tz::TimeZone;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::fsutil::Retry;
+
 pub const DEFAULT_KEEP_NUM: usize = 100;
+pub const DEFAULT_BACKUP_DATE_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+pub const DEFAULT_SYNCTHING_DATE_FORMAT: &str = "%Y%m%d-%H%M%S";
+pub const DEFAULT_BACKUP_TIMEZONE: &str = "utc";
+pub const DEFAULT_IO_RETRIES: u32 = 3;
+pub const DEFAULT_IO_RETRY_DELAY_MS: u64 = 200;
+pub const DEFAULT_CONFLICT_LOOP_THRESHOLD: u32 = 5;
+pub const PATTERN: &str = r"^taskchampion\.sync-conflict-(\d{8}-\d{6})-([A-Z0-9]{7})\.sqlite3$";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// Only report what resolving would do, as if `--dry-run` was always passed. `--apply` overrides this for one run
+    pub dry_run: Option<bool>,
+
+    /// Back up the databases to the state dir before replacing or removing them
+    pub backup: Option<bool>,
+
+    /// Write each run's backups into a single compressed archive instead of copying the databases as-is
+    pub compress_backups: Option<bool>,
+
     /// Number of history records to keep in application cache dir
     pub keep: Option<usize>,
 
-    /// If omitted, defaults to taskwarrior's default (${XDG_DATA_HOME}/task/)
+    /// Remove history records older than this many days, in addition to the `keep` limit
+    pub keep_days: Option<u64>,
+
+    /// If omitted, defaults to taskwarrior's default ($TASKDATA, ${XDG_DATA_HOME}/task/, or ~/.local/share/task/)
     pub task_dir: Option<PathBuf>,
+
+    /// Taskwarrior binary to use. If omitted, `task` is looked up on the $PATH
+    pub task_bin: Option<PathBuf>,
+
+    /// Several taskwarrior data directories to resolve in one run, each with its own backups. Takes precedence over
+    /// `task_dir`
+    pub task_dirs: Option<Vec<PathBuf>>,
+
+    /// Directory for scratch copies of the databases while merging, instead of `$TMPDIR`
+    pub temp_dir: Option<PathBuf>,
+
+    /// Directory to keep backups in. If omitted, defaults to the XDG state dir
+    /// (${XDG_STATE_HOME}/syncthing-task-resolve/ or ~/.local/state/syncthing-task-resolve/)
+    pub state_dir: Option<PathBuf>,
+
+    /// Regex matching conflict file names. The timestamp is taken from the group named `timestamp` (or group 1)
+    /// and the device ID from the group named `device` (or group 2)
+    pub conflict_pattern: Option<String>,
+
+    /// How to combine the snapshots of a task that differ between databases
+    pub merge_strategy: Option<MergeStrategy>,
+
+    /// How to read the databases: with `task export`, or in-process with the taskchampion library
+    pub read_backend: Option<Backend>,
+
+    /// How to write the merged database: with `task import`, or in-process with the taskchampion library
+    pub write_backend: Option<Backend>,
+
+    /// How many databases to read at once. If omitted, one per CPU
+    pub parallelism: Option<NonZeroUsize>,
+
+    /// Keep a task completed or deleted when another device, which never saw that, still has it open
+    pub sticky_completion: Option<bool>,
+
+    /// Device IDs (`main` for the main database) whose snapshots win over those of other devices, however old they
+    /// are, highest priority first. Snapshots from devices of the same priority, or not listed, go by modified time
+    pub winner_priority: Option<Vec<String>>,
+
+    /// Warn about a task that conflicted in more than this many resolves in a row, as when two devices keep
+    /// overwriting each other's changes
+    pub conflict_loop_threshold: Option<u32>,
+
+    /// Leave the conflict files of such a task on disk (after merging them), so the loop can be looked into
+    pub keep_looping_conflicts: Option<bool>,
+
+    /// Remove the conflict files once they are merged. Without it they are merged and backed up, but left on disk
+    pub delete_conflicts: Option<bool>,
+
+    /// How many times to retry backing up, removing, or replacing a file after a transient I/O error
+    pub io_retries: Option<u32>,
+
+    /// Milliseconds to wait before the first retry, doubled for each retry after it
+    pub io_retry_delay_ms: Option<u64>,
+
+    /// File to append a line to for each task whose conflict was resolved, saying which device won. Rotated once it
+    /// grows past 1 MiB
+    pub audit_log: Option<PathBuf>,
+
+    /// Show a desktop notification when conflicts were resolved
+    pub notify: Option<bool>,
+
+    /// Program to run after a merge was applied, with the backup dir and the number of conflict files as arguments
+    pub post_resolve_hook: Option<PathBuf>,
+
+    /// Also merge conflict copies of taskwarrior 2.x's `pending.data`, `completed.data`, and `backlog.data`
+    pub legacy_format: Option<bool>,
+
+    /// strftime format of the backup directory names in the state dir
+    pub backup_date_format: Option<String>,
+
+    /// Time zone of the backup directory names: `utc`, `local`, or an IANA name like `Europe/Berlin`
+    pub backup_timezone: Option<String>,
+
+    /// strftime format of the timestamp syncthing puts in conflict file names
+    pub syncthing_date_format: Option<String>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            dry_run: Some(false),
+            backup: Some(true),
+            compress_backups: Some(false),
             keep: Some(DEFAULT_KEEP_NUM),
+            keep_days: None,
             task_dir: None,
+            task_bin: None,
+            task_dirs: None,
+            temp_dir: None,
+            state_dir: None,
+            conflict_pattern: None,
+            merge_strategy: Some(MergeStrategy::default()),
+            read_backend: Some(Backend::default()),
+            write_backend: Some(Backend::default()),
+            parallelism: None,
+            sticky_completion: Some(false),
+            winner_priority: None,
+            conflict_loop_threshold: Some(DEFAULT_CONFLICT_LOOP_THRESHOLD),
+            keep_looping_conflicts: Some(false),
+            delete_conflicts: Some(true),
+            io_retries: Some(DEFAULT_IO_RETRIES),
+            io_retry_delay_ms: Some(DEFAULT_IO_RETRY_DELAY_MS),
+            audit_log: None,
+            notify: Some(false),
+            post_resolve_hook: None,
+            legacy_format: Some(false),
+            backup_date_format: Some(DEFAULT_BACKUP_DATE_FORMAT.to_owned()),
+            backup_timezone: Some(DEFAULT_BACKUP_TIMEZONE.to_owned()),
+            syncthing_date_format: Some(DEFAULT_SYNCTHING_DATE_FORMAT.to_owned()),
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file at `path`, which must exist
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.is_file() {
+            bail!("Config file {} does not exist", path.display());
+        }
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Unable to read config file {}", path.display()))?;
+        let config: Self =
+            toml::from_str(&contents).map_err(|e| parse_error(path, &contents, &e))?;
+        config
+            .check()
+            .wrap_err_with(|| format!("Invalid config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Check the values that can't be checked while parsing
+    fn check(&self) -> Result<()> {
+        check_date_format("backup_date_format", self.backup_date_format())?;
+        check_date_format("syncthing_date_format", self.syncthing_date_format())?;
+        if strtime::format(self.backup_date_format(), sample_date())?.contains('/') {
+            bail!(
+                "backup_date_format '{}' must not produce a '/', it is used as a directory name",
+                self.backup_date_format()
+            );
         }
+
+        self.backup_timezone()?;
+        self.conflict_regex()?;
+        Ok(())
+    }
+
+    /// Whether taskwarrior is run at all, rather than reading and writing everything with taskchampion
+    pub fn uses_task_bin(&self) -> bool {
+        self.read_backend.unwrap_or_default() == Backend::Cli
+            || self.write_backend.unwrap_or_default() == Backend::Cli
+    }
+
+    pub fn retry(&self) -> Retry {
+        Retry {
+            retries: self.io_retries.unwrap_or(DEFAULT_IO_RETRIES),
+            delay: Duration::from_millis(
+                self.io_retry_delay_ms.unwrap_or(DEFAULT_IO_RETRY_DELAY_MS),
+            ),
+        }
+    }
+
+    pub fn backup_date_format(&self) -> &str {
+        self.backup_date_format
+            .as_deref()
+            .unwrap_or(DEFAULT_BACKUP_DATE_FORMAT)
+    }
+
+    pub fn backup_timezone(&self) -> Result<TimeZone> {
+        match self
+            .backup_timezone
+            .as_deref()
+            .unwrap_or(DEFAULT_BACKUP_TIMEZONE)
+        {
+            "utc" | "UTC" => Ok(TimeZone::UTC),
+            "local" => Ok(TimeZone::system()),
+            name => TimeZone::get(name).wrap_err_with(|| {
+                format!(
+                    "Invalid backup_timezone '{}', expected 'utc', 'local', or an IANA time zone name",
+                    name
+                )
+            }),
+        }
+    }
+
+    pub fn syncthing_date_format(&self) -> &str {
+        self.syncthing_date_format
+            .as_deref()
+            .unwrap_or(DEFAULT_SYNCTHING_DATE_FORMAT)
+    }
+
+    /// Compile the conflict file name pattern, checking that it has groups for the timestamp and device
+    pub fn conflict_regex(&self) -> Result<Regex> {
+        let pattern = self.conflict_pattern.as_deref().unwrap_or(PATTERN);
+        let re = Regex::new(pattern)
+            .wrap_err_with(|| format!("Invalid conflict_pattern '{}'", pattern))?;
+
+        let has_group = |name: &str, index: usize| {
+            re.capture_names().flatten().any(|n| n == name) || re.captures_len() > index
+        };
+        if !has_group("timestamp", 1) || !has_group("device", 2) {
+            bail!(
+                "conflict_pattern '{}' must have a group for the timestamp (named 'timestamp' or group 1) and one for the device (named 'device' or group 2)",
+                pattern
+            );
+        }
+
+        Ok(re)
+    }
+
+    /// The same settings, with defaults filled in for the ones that aren't set
+    pub fn with_defaults(self) -> Self {
+        let default = Self::default();
+        Self {
+            dry_run: self.dry_run.or(default.dry_run),
+            backup: self.backup.or(default.backup),
+            compress_backups: self.compress_backups.or(default.compress_backups),
+            keep: self.keep.or(default.keep),
+            keep_days: self.keep_days,
+            task_dir: self.task_dir,
+            task_bin: self.task_bin,
+            task_dirs: self.task_dirs,
+            temp_dir: self.temp_dir,
+            state_dir: self.state_dir,
+            conflict_pattern: self.conflict_pattern.or(Some(PATTERN.to_owned())),
+            merge_strategy: self.merge_strategy.or(default.merge_strategy),
+            read_backend: self.read_backend.or(default.read_backend),
+            write_backend: self.write_backend.or(default.write_backend),
+            parallelism: self.parallelism.or(default.parallelism),
+            sticky_completion: self.sticky_completion.or(default.sticky_completion),
+            winner_priority: self.winner_priority.or(default.winner_priority),
+            conflict_loop_threshold: self
+                .conflict_loop_threshold
+                .or(default.conflict_loop_threshold),
+            keep_looping_conflicts: self
+                .keep_looping_conflicts
+                .or(default.keep_looping_conflicts),
+            delete_conflicts: self.delete_conflicts.or(default.delete_conflicts),
+            io_retries: self.io_retries.or(default.io_retries),
+            io_retry_delay_ms: self.io_retry_delay_ms.or(default.io_retry_delay_ms),
+            audit_log: self.audit_log.or(default.audit_log),
+            notify: self.notify.or(default.notify),
+            post_resolve_hook: self.post_resolve_hook,
+            legacy_format: self.legacy_format.or(default.legacy_format),
+            backup_date_format: self.backup_date_format.or(default.backup_date_format),
+            backup_timezone: self.backup_timezone.or(default.backup_timezone),
+            syncthing_date_format: self.syncthing_date_format.or(default.syncthing_date_format),
+        }
+    }
+
+    /// Read the config file at `path`, writing out the default config first if there isn't one yet
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        if path.is_file() {
+            return Self::load(path);
+        }
+
+        let config = Self::default();
+        let contents = toml::to_string_pretty(&config)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+        Ok(config)
+    }
+}
+
+/// A date with every field set to a distinct value, so a format that drops or mixes up fields fails to round trip
+fn sample_date() -> DateTime {
+    date(2024, 11, 22).at(13, 14, 15, 0)
+}
+
+/// Check that `format` can both format and parse back a date, so timestamps are never silently misread
+fn check_date_format(key: &str, format: &str) -> Result<()> {
+    let sample = sample_date();
+    let formatted = strtime::format(format, sample)
+        .wrap_err_with(|| format!("Invalid {} '{}'", key, format))?;
+    let parsed = DateTime::strptime(format, &formatted).wrap_err_with(|| {
+        format!(
+            "Invalid {} '{}', unable to parse back '{}'",
+            key, format, formatted
+        )
+    })?;
+    if parsed != sample {
+        bail!(
+            "{} '{}' must include the year, month, day, hour, minute, and second ('{}' was read back as {})",
+            key,
+            format,
+            formatted,
+            parsed
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategy {
+    /// Keep the whole snapshot that was modified most recently
+    #[default]
+    LastWriterWins,
+
+    /// Take the most recently modified value of each attribute, combining tags and annotations
+    FieldLevel,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// Run the taskwarrior binary
+    #[default]
+    Cli,
+
+    /// Open the sqlite databases directly with the taskchampion library, without running taskwarrior
+    Native,
+}
+
+/// The error for a config file that isn't valid TOML (or doesn't fit the config), pointing at the offending spot, e.g.
+///
+/// ```text
+/// Unable to parse config.toml, line 3 column 8: invalid type: string "5", expected usize
+///
+///     keep = "5"
+///            ^
+/// ```
+fn parse_error(path: &Path, contents: &str, e: &toml::de::Error) -> Report {
+    let Some(span) = e.span() else {
+        return eyre!("Unable to parse {}: {}", path.display(), e.message());
+    };
+    let before = &contents[..span.start];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line = before.matches('\n').count() + 1;
+    let column = before[line_start..].chars().count() + 1;
+    let text = contents[line_start..].lines().next().unwrap_or("");
+    // Tabs are kept, so the caret lines up however wide the terminal draws them
+    let indent: String = before[line_start..]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    eyre!(
+        "Unable to parse {}, line {} column {}: {}\n\n    {}\n    {}^",
+        path.display(),
+        line,
+        column,
+        e.message().trim_end(),
+        text,
+        indent
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Config {
+        toml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn check_accepts_the_default_conflict_pattern() {
+        parse("").check().unwrap();
+    }
+
+    #[test]
+    fn check_rejects_a_conflict_pattern_without_the_groups() {
+        let e = parse(r#"conflict_pattern = '^conflict-\d+\.sqlite3$'"#)
+            .check()
+            .unwrap_err();
+        assert!(format!("{:#}", e).contains("must have a group for the timestamp"));
+    }
+
+    #[test]
+    fn check_rejects_an_invalid_conflict_pattern() {
+        let e = parse(r#"conflict_pattern = '(unclosed'"#)
+            .check()
+            .unwrap_err();
+        assert!(format!("{:#}", e).contains("Invalid conflict_pattern"));
+    }
+
+    #[test]
+    fn check_accepts_named_groups_in_any_order() {
+        parse(r#"conflict_pattern = '^(?P<device>[A-Z]+)-(?P<timestamp>\d{8}-\d{6})\.db$'"#)
+            .check()
+            .unwrap();
     }
 }
@@ -0,0 +1,221 @@
+use color_eyre::Result;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use task_hookrs::task::Task;
+
+/// Attributes that change on every edit and would only add noise to a diff
+const IGNORED_FIELDS: &[&str] = &["id", "modified", "urgency"];
+
+/// Unchanged lines shown around each change in a unified diff
+const CONTEXT_LINES: usize = 3;
+
+/// Print the attributes that differ between the task in the main db (if it exists there) and the merged task.
+/// Prints nothing when they are the same.
+pub fn print_task_diff(current: Option<&Task>, merged: &Task) -> Result<()> {
+    let old = match current {
+        Some(task) => fields(task)?,
+        None => Map::new(),
+    };
+    let new = fields(merged)?;
+
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    let mut changes = Vec::new();
+    for key in keys {
+        if IGNORED_FIELDS.contains(&key.as_str()) {
+            continue;
+        }
+        match (old.get(key), new.get(key)) {
+            (Some(a), Some(b)) if a == b => {}
+            (Some(a), Some(b)) => {
+                changes.push(format!("  ~ {}: {} -> {}", key, display(a), display(b)))
+            }
+            (Some(a), None) => changes.push(format!("  - {}: {}", key, display(a))),
+            (None, Some(b)) => changes.push(format!("  + {}: {}", key, display(b))),
+            (None, None) => {}
+        }
+    }
+
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    match current {
+        Some(_) => println!("task {} ({})", merged.uuid(), merged.description()),
+        None => println!("task {} ({}) [new]", merged.uuid(), merged.description()),
+    }
+    for change in changes {
+        println!("{}", change);
+    }
+
+    Ok(())
+}
+
+/// A task's attributes as they would be exported by taskwarrior
+fn fields(task: &Task) -> Result<Map<String, Value>> {
+    match serde_json::to_value(task)? {
+        Value::Object(map) => Ok(map),
+        _ => Ok(Map::new()),
+    }
+}
+
+/// Format an attribute value without JSON quoting where possible
+fn display(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Array(items) if items.iter().all(Value::is_string) => items
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(", "),
+        other => other.to_string(),
+    }
+}
+
+/// A unified diff, as `diff -u` prints it, between two `task export` outputs. The tasks are sorted by UUID and printed
+/// one attribute per line, leaving out the `id` taskwarrior numbers its working set with. Empty when they are the same
+pub fn unified_export_diff(old: &[Value], new: &[Value], old_name: &str, new_name: &str) -> String {
+    let mut tasks: BTreeMap<String, (Vec<String>, Vec<String>)> = BTreeMap::new();
+    for (tasks_out, side) in [(old, 0), (new, 1)] {
+        for task in tasks_out {
+            let uuid = task["uuid"].as_str().unwrap_or_default().to_owned();
+            let mut task = task.clone();
+            if let Value::Object(fields) = &mut task {
+                fields.remove("id");
+            }
+            let pretty = serde_json::to_string_pretty(&task).unwrap_or_default();
+            let lines = pretty.lines().map(str::to_owned);
+            let entry = tasks.entry(uuid).or_default();
+            match side {
+                0 => entry.0.extend(lines),
+                _ => entry.1.extend(lines),
+            }
+        }
+    }
+
+    // Diffing task by task keeps each diff small, however many tasks there are
+    let mut old_lines = Vec::new();
+    let mut new_lines = Vec::new();
+    let mut edits = Vec::new();
+    for (old_task, new_task) in tasks.values() {
+        for (op, i, j) in line_edits(old_task, new_task) {
+            edits.push((op, old_lines.len() + i, new_lines.len() + j));
+        }
+        old_lines.extend(old_task.iter().map(String::as_str));
+        new_lines.extend(new_task.iter().map(String::as_str));
+    }
+    if edits.iter().all(|(op, _, _)| *op == Op::Same) {
+        return String::new();
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    for (start, end) in hunks(&edits) {
+        let hunk = &edits[start..end];
+        let old_count = hunk.iter().filter(|(op, _, _)| *op != Op::Added).count();
+        let new_count = hunk.iter().filter(|(op, _, _)| *op != Op::Removed).count();
+        // An empty side is numbered by the line before it, as diff does
+        let (_, old_start, new_start) = hunk[0];
+        let old_start = old_start + usize::from(old_count > 0);
+        let new_start = new_start + usize::from(new_count > 0);
+        let _ = writeln!(
+            out,
+            "@@ -{},{} +{},{} @@",
+            old_start, old_count, new_start, new_count
+        );
+        for &(op, i, j) in hunk {
+            let _ = match op {
+                Op::Same => writeln!(out, " {}", old_lines[i]),
+                Op::Removed => writeln!(out, "-{}", old_lines[i]),
+                Op::Added => writeln!(out, "+{}", new_lines[j]),
+            };
+        }
+    }
+    out
+}
+
+/// What happened to a line between the old and the new text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Same,
+    Removed,
+    Added,
+}
+
+/// The shortest list of line edits turning `old` into `new`, with Myers' algorithm. Each edit has the index of its
+/// line in `old` and in `new`; for an added or removed line, the index on the other side is where it would go
+fn line_edits(old: &[String], new: &[String]) -> Vec<(Op, usize, usize)> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let offset = n + m;
+    let at = |k: isize| (k + offset) as usize;
+
+    // The furthest x reached on each diagonal k = x - y, kept for every number of edits d to walk back through
+    let mut v = vec![0isize; 2 * (n + m) as usize + 2];
+    let mut trace = Vec::new();
+    'search: for d in 0..=offset {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let mut x = match k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                true => v[at(k + 1)],
+                false => v[at(k - 1)] + 1,
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k = match k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+            true => k + 1,
+            false => k - 1,
+        };
+        let prev_x = v[at(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push((Op::Same, x as usize, y as usize));
+        }
+        if d > 0 {
+            match x == prev_x {
+                true => edits.push((Op::Added, x as usize, prev_y as usize)),
+                false => edits.push((Op::Removed, prev_x as usize, y as usize)),
+            }
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+    edits.reverse();
+    edits
+}
+
+/// The ranges of `edits` to show as hunks: each change with the unchanged lines around it, merging changes that are
+/// close enough for their context to touch
+fn hunks(edits: &[(Op, usize, usize)]) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (i, _) in edits
+        .iter()
+        .enumerate()
+        .filter(|(_, (op, _, _))| *op != Op::Same)
+    {
+        let start = i.saturating_sub(CONTEXT_LINES);
+        let end = (i + 1 + CONTEXT_LINES).min(edits.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
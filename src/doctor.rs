@@ -0,0 +1,84 @@
+use crate::backup;
+use crate::config::Config;
+use crate::target::Target;
+use crate::taskwarrior;
+use crate::MAIN_DB_NAME;
+use color_eyre::Result;
+use std::path::Path;
+
+/// Print a diagnostic report of the environment. Returns false if something resolving needs is missing
+pub fn doctor(
+    task_bin: Option<&Path>,
+    config_file: &Path,
+    config: &Config,
+    targets: &[Target],
+) -> Result<bool> {
+    let mut ok = true;
+
+    match taskwarrior::find_task_bin(task_bin) {
+        Ok(task_bin) => match taskwarrior::version(&task_bin) {
+            Ok(version) => println!("task binary: {} ({})", task_bin.display(), version),
+            Err(e) => {
+                println!(
+                    "task binary: {} (unable to get version: {})",
+                    task_bin.display(),
+                    e
+                );
+                ok = false;
+            }
+        },
+        Err(e) if config.uses_task_bin() => {
+            println!("task binary: MISSING ({})", e);
+            ok = false;
+        }
+        Err(_) => println!("task binary: not found, not needed with the native backends"),
+    }
+
+    if config_file.is_file() {
+        println!("config file: {}", config_file.display());
+    } else {
+        println!(
+            "config file: {} (not created yet, using defaults)",
+            config_file.display()
+        );
+    }
+    for line in toml::to_string_pretty(config)?.lines() {
+        println!("  {}", line);
+    }
+
+    for target in targets {
+        if !target.task_dir.is_dir() {
+            println!("task dir: {} MISSING", target.task_dir.display());
+            ok = false;
+            continue;
+        }
+        println!("task dir: {}", target.task_dir.display());
+
+        let main_db = target.task_dir.join(MAIN_DB_NAME);
+        let main_db_state = if main_db.is_file() {
+            "present"
+        } else {
+            "missing"
+        };
+        println!("  main database: {}", main_db_state);
+        match crate::detect_conflicts(&target.task_dir, config) {
+            Ok(conflicts) => println!("  conflict files: {}", conflicts.len()),
+            Err(e) => {
+                println!("  conflict files: unable to scan ({})", e);
+                ok = false;
+            }
+        }
+
+        let num_backups = backup::list(&target.state_dir, config.backup_date_format())?.len();
+        println!(
+            "  state dir: {} ({} backups)",
+            target.state_dir.display(),
+            num_backups
+        );
+    }
+
+    if !ok {
+        println!("Some checks failed, see above");
+    }
+    Ok(ok)
+}
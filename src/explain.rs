@@ -0,0 +1,101 @@
+use crate::config::MergeStrategy;
+use crate::history;
+use crate::{Conflict, ResolveOptions};
+use color_eyre::{eyre::bail, Result};
+use task_hookrs::task::Task;
+use uuid::Uuid;
+
+/// Print every snapshot of the task `uuid` in `conflicts`, which one the merge picks and why, and the merge result
+pub fn explain(conflicts: &[Conflict], opts: &ResolveOptions, uuid: &Uuid) -> Result<()> {
+    let hist = crate::read_history(conflicts, opts)?;
+    let snapshots = hist.snapshots(uuid);
+    if snapshots.is_empty() {
+        bail!(
+            "Task {} is in none of the {} databases",
+            uuid,
+            conflicts.len()
+        );
+    }
+    let merged = history::merge_snapshots(
+        snapshots,
+        opts.merge_strategy,
+        opts.sticky_completion,
+        opts.winner_priority,
+    )?;
+    let indented = |task: &Task| -> Result<String> {
+        let json = serde_json::to_string_pretty(task)?;
+        Ok(json.lines().map(|line| format!("    {}\n", line)).collect())
+    };
+    let (strategy, how) = match opts.merge_strategy {
+        MergeStrategy::LastWriterWins => (
+            "last-writer-wins",
+            "The picked snapshot is taken as a whole",
+        ),
+        MergeStrategy::FieldLevel => (
+            "field-level",
+            "Each attribute is taken from the newest snapshot that has it, and tags and dependencies are combined from all of them",
+        ),
+    };
+
+    println!(
+        "Task {} ({}): {} snapshot{}, merged with {}{}",
+        uuid,
+        merged.task.description(),
+        snapshots.len(),
+        if snapshots.len() == 1 { "" } else { "s" },
+        strategy,
+        match opts.sticky_completion {
+            true => " and sticky completion",
+            false => "",
+        }
+    );
+    for snapshot in snapshots {
+        let modified = snapshot
+            .task
+            .modified()
+            .unwrap_or(snapshot.task.entry())
+            .format("%Y-%m-%d %H:%M:%S");
+        let picked = match snapshot.path == merged.winner.path {
+            true => " [picked]",
+            false => "",
+        };
+        println!();
+        println!(
+            "  {}: {}, modified {}{}",
+            snapshot.source,
+            snapshot.path.display(),
+            modified,
+            picked
+        );
+        print!("{}", indented(&snapshot.task)?);
+    }
+
+    println!();
+    if !merged.conflicted {
+        println!("The snapshots don't differ, so there was nothing to decide");
+    }
+    for other in snapshots.iter().filter(|s| s.path != merged.winner.path) {
+        println!(
+            "Picked {} over {}: {}",
+            merged.winner.source,
+            other.source,
+            history::why_preferred(&merged.winner, other, opts.winner_priority)
+        );
+    }
+    println!(
+        "{}. Annotations are combined from every snapshot, and the earliest entry time is kept",
+        how
+    );
+    if opts.sticky_completion && merged.task.status() != merged.winner.task.status() {
+        println!(
+            "Its status is {} rather than {}, as another snapshot completed or deleted it (sticky_completion)",
+            merged.task.status(),
+            merged.winner.task.status()
+        );
+    }
+
+    println!();
+    println!("Merged:");
+    print!("{}", indented(&merged.task)?);
+    Ok(())
+}
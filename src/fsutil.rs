@@ -0,0 +1,209 @@
+use color_eyre::{eyre::WrapErr, Report, Result};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// How to retry filesystem operations that fail with a transient error, as network mounts occasionally do
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    /// Attempts after the first one
+    pub retries: u32,
+
+    /// Wait before the first retry, doubled for each one after
+    pub delay: Duration,
+}
+
+impl Retry {
+    /// Run `op` until it succeeds, fails with an error that isn't transient, or runs out of retries
+    pub fn run<T>(&self, what: &str, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut delay = self.delay;
+        for attempt in 1.. {
+            match op() {
+                Err(e) if attempt <= self.retries && is_transient(&e) => {
+                    warn!(
+                        "{} failed ({:#}), retrying in {:?} ({} of {})",
+                        what, e, delay, attempt, self.retries
+                    );
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+        unreachable!("the loop only ends by returning")
+    }
+}
+
+/// Whether an error is likely to go away when the operation is simply tried again
+fn is_transient(e: &Report) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| {
+            use io::ErrorKind::*;
+            // EIO is what NFS and SMB mounts report for most hiccups, but std has no ErrorKind for it
+            matches!(
+                e.kind(),
+                Interrupted | WouldBlock | TimedOut | ResourceBusy | StaleNetworkFileHandle
+            ) || (cfg!(unix) && e.raw_os_error() == Some(5))
+        })
+}
+
+/// Total size of the files in the directory at `path`, and in all directories below it
+pub fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += match metadata.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => metadata.len(),
+        };
+    }
+    Ok(size)
+}
+
+/// A number of bytes for people, e.g. `1.5 MiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Whether `path` is `dir` or somewhere below it, following symlinks. Neither has to exist yet
+pub fn is_within(path: &Path, dir: &Path) -> io::Result<bool> {
+    Ok(real_path(path)?.starts_with(real_path(dir)?))
+}
+
+/// `path` made absolute, with symlinks resolved as far as it exists
+fn real_path(path: &Path) -> io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    let mut existing = path.as_path();
+    let mut rest = Vec::new();
+    loop {
+        match fs::canonicalize(existing) {
+            Ok(real) => return Ok(rest.iter().rev().fold(real, |real, part| real.join(part))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let (Some(parent), Some(name)) = (existing.parent(), existing.file_name()) else {
+                    return Ok(path);
+                };
+                rest.push(name);
+                existing = parent;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Move `src` over `dest` so that `dest` is never observed half-written.
+/// A rename is atomic on the same filesystem. Across filesystems `src` is first copied to a sibling of `dest`, which
+/// is then renamed into place.
+/// When `dest` already exists, the new file gets its permissions, and on Unix its owner and group, so running under a
+/// different user or umask than taskwarrior doesn't change who can read the database.
+pub fn replace_atomically(src: &Path, dest: &Path) -> Result<()> {
+    let original = fs::metadata(dest).ok();
+    if let Some(original) = &original {
+        keep_metadata(original, src)?;
+    }
+
+    match fs::rename(src, dest) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            debug!(
+                "Unable to rename {} to {} across filesystems, copying first",
+                src.display(),
+                dest.display()
+            );
+        }
+        Err(e) => {
+            return Err(e).wrap_err_with(|| {
+                format!("Unable to move {} to {}", src.display(), dest.display())
+            })
+        }
+    }
+
+    let tmp = tmp_sibling(dest);
+    fs::copy(src, &tmp)
+        .wrap_err_with(|| format!("Unable to copy {} to {}", src.display(), tmp.display()))?;
+    if let Some(original) = &original {
+        keep_metadata(original, &tmp)?;
+    }
+    if let Err(e) = fs::rename(&tmp, dest) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e)
+            .wrap_err_with(|| format!("Unable to move {} to {}", tmp.display(), dest.display()));
+    }
+    Ok(())
+}
+
+/// Whether the files at `a` and `b` have exactly the same contents. Sizes are compared first, so files that differ in
+/// size are never read
+pub fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    loop {
+        let chunk_a = a.fill_buf()?;
+        let chunk_b = b.fill_buf()?;
+        let len = chunk_a.len().min(chunk_b.len());
+        if len == 0 {
+            // Either both ended, or one is shorter after all because it changed since the sizes were compared
+            return Ok(chunk_a.is_empty() && chunk_b.is_empty());
+        }
+        if chunk_a[..len] != chunk_b[..len] {
+            return Ok(false);
+        }
+        a.consume(len);
+        b.consume(len);
+    }
+}
+
+/// `<dest>.tmp`, in the same directory as `dest`
+pub fn tmp_sibling(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".tmp");
+    dest.with_file_name(name)
+}
+
+/// Give `path` the permissions, and on Unix the owner and group, from `original`.
+/// Only root can give a file away to another user, so failing to change the owner is not an error
+fn keep_metadata(original: &fs::Metadata, path: &Path) -> Result<()> {
+    fs::set_permissions(path, original.permissions())
+        .wrap_err_with(|| format!("Unable to set the permissions of {}", path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let current = fs::metadata(path)?;
+        if (current.uid(), current.gid()) != (original.uid(), original.gid()) {
+            if let Err(e) =
+                std::os::unix::fs::chown(path, Some(original.uid()), Some(original.gid()))
+            {
+                warn!(
+                    "Unable to give {} the owner {}:{} of the file it replaces: {}",
+                    path.display(),
+                    original.uid(),
+                    original.gid(),
+                    e
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
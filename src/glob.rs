@@ -0,0 +1,184 @@
+//! Shell style wildcards for `--exclude`, matched against single file names by translating them to regexes.
+
+// TODO: match them with globset once it can be added as a dependency
+
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+
+/// A shell style wildcard pattern, matched against a whole file name.
+/// Supports `*`, `?`, `[abc]`, `[!abc]`, `{a,b}`, and `\` to escape the next character
+#[derive(Debug, Clone)]
+pub struct Glob {
+    pattern: String,
+    re: Regex,
+}
+
+impl Glob {
+    pub fn is_match(&self, name: &str) -> bool {
+        self.re.is_match(name)
+    }
+}
+
+impl FromStr for Glob {
+    type Err = regex::Error;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let re = Regex::new(&to_regex(pattern))?;
+        Ok(Self {
+            pattern: pattern.to_owned(),
+            re,
+        })
+    }
+}
+
+impl fmt::Display for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// The regex matching the same names as the glob `pattern`
+fn to_regex(pattern: &str) -> String {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut re = String::from("^");
+    let mut braces = 0;
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                re.push_str(&regex::escape(&chars[i].to_string()));
+            }
+            '[' => match class(&chars[i + 1..]) {
+                Some((class, len)) => {
+                    re.push_str(&class);
+                    i += len;
+                }
+                // An unclosed bracket is just a bracket
+                None => re.push_str(r"\["),
+            },
+            '{' => {
+                braces += 1;
+                re.push_str("(?:");
+            }
+            ',' if braces > 0 => re.push('|'),
+            '}' if braces > 0 => {
+                braces -= 1;
+                re.push(')');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+
+    // An unclosed brace groups its alternatives up to the end of the pattern
+    for _ in 0..braces {
+        re.push(')');
+    }
+    re.push('$');
+    re
+}
+
+/// The regex class for the glob class starting right after a `[`, and how many characters it took up including the
+/// closing `]`. None when the class is never closed
+fn class(chars: &[char]) -> Option<(String, usize)> {
+    let mut re = String::from("[");
+    let mut i = 0;
+    if chars.first() == Some(&'!') {
+        re.push('^');
+        i += 1;
+    }
+    // A `]` right at the start is part of the class, rather than closing it
+    let start = i;
+
+    while i < chars.len() {
+        match chars[i] {
+            ']' if i > start => {
+                re.push(']');
+                return Some((re, i + 1));
+            }
+            // Characters the regex crate gives a meaning inside classes, but globs don't
+            c @ ('[' | ']' | '\\' | '&' | '~' | '^') => {
+                re.push('\\');
+                re.push(c);
+            }
+            c => re.push(c),
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        pattern.parse::<Glob>().unwrap().is_match(name)
+    }
+
+    #[test]
+    fn star() {
+        assert!(matches("*", ""));
+        assert!(matches("*.sqlite3", "taskchampion.sqlite3"));
+        assert!(matches(
+            "*ABCDEFG*",
+            "taskchampion.sync-conflict-20240102-030405-ABCDEFG.sqlite3"
+        ));
+        assert!(!matches("*.sqlite3", "taskchampion.sqlite3.bak"));
+    }
+
+    #[test]
+    fn question_mark() {
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "ac"));
+        assert!(!matches("a?c", "abbc"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("[abc]x", "bx"));
+        assert!(!matches("[abc]x", "dx"));
+        assert!(matches("[!abc]x", "dx"));
+        assert!(!matches("[!abc]x", "ax"));
+        assert!(matches("[a-c]", "b"));
+        assert!(!matches("[a-c]", "d"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[\\^&~[]", "^"));
+        assert!(matches("[\\^&~[]", "["));
+        // An unclosed bracket is just a bracket
+        assert!(matches("[a", "[a"));
+    }
+
+    #[test]
+    fn braces() {
+        assert!(matches("*.{db,sqlite3}", "a.db"));
+        assert!(matches("*.{db,sqlite3}", "a.sqlite3"));
+        assert!(!matches("*.{db,sqlite3}", "a.txt"));
+        assert!(matches("a,b", "a,b"));
+        assert!(matches("{a,b", "b"));
+    }
+
+    #[test]
+    fn escaping() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+        assert!(matches("\\?", "?"));
+        assert!(!matches("\\?", "x"));
+        assert!(matches("\\[a]", "[a]"));
+        assert!(!matches("\\[a]", "a"));
+        // Regex syntax is matched literally
+        assert!(matches("a.b+(c)$", "a.b+(c)$"));
+        assert!(!matches("a.b", "axb"));
+    }
+
+    #[test]
+    fn whole_name() {
+        assert!(!matches("conflict", "sync-conflict-1"));
+        assert_eq!("*.{a,b}".parse::<Glob>().unwrap().to_string(), "*.{a,b}");
+    }
+}
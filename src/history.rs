@@ -0,0 +1,681 @@
+use crate::config::MergeStrategy;
+use crate::progress::Progress;
+use crate::Source;
+use color_eyre::{eyre::bail, Result};
+use jiff::Timestamp;
+use serde_json::{Map, Value};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use task_hookrs::annotation::Annotation;
+use task_hookrs::date::Date;
+use task_hookrs::status::TaskStatus;
+use task_hookrs::task::Task;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Attributes whose values are lists that should be combined across snapshots instead of overwritten.
+/// Annotations are always combined, whatever the merge strategy, see `all_annotations`.
+const UNION_FIELDS: &[&str] = &["tags"];
+
+/// Attributes that taskwarrior computes on export, so they can differ between snapshots that are otherwise equal
+const COMPUTED_FIELDS: &[&str] = &["id", "urgency"];
+
+/// One version of a task, and the database it was read from
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub task: Task,
+    pub source: Source,
+    pub path: PathBuf,
+}
+
+/// The result of merging all snapshots of a task
+#[derive(Debug)]
+pub struct Merged {
+    pub task: Task,
+
+    /// The snapshot that won. For field-level merges, this is the most recently modified one
+    pub winner: Snapshot,
+
+    /// Number of snapshots the task was merged from
+    pub snapshots: usize,
+
+    /// Where each of the snapshots was read from
+    pub origins: Vec<(Source, PathBuf)>,
+
+    /// Whether the snapshots disagreed with each other
+    pub conflicted: bool,
+}
+
+/// Everything `History::merge` found out
+#[derive(Debug)]
+pub struct MergeResult {
+    pub merged: Vec<Merged>,
+
+    /// Tasks whose snapshots disagreed across databases
+    pub conflicted_uuids: Vec<Uuid>,
+
+    /// Number of tasks found in only one database, so there was nothing to merge
+    pub unique_uuids: usize,
+}
+
+/// Every snapshot of every task, as read from each of the databases being merged
+#[derive(Debug, Default)]
+pub struct History {
+    tasks: HashMap<Uuid, Vec<Snapshot>>,
+}
+impl History {
+    pub fn new() -> Self {
+        Self {
+            tasks: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, task: Task, source: &Source, path: &Path) {
+        let key = *task.uuid();
+        let snapshot = Snapshot {
+            task,
+            source: source.clone(),
+            path: path.to_owned(),
+        };
+
+        if let Some(vals) = self.tasks.get_mut(&key) {
+            vals.push(snapshot);
+        } else {
+            self.tasks.insert(key, vec![snapshot]);
+        }
+    }
+
+    /// Number of tasks
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Every task read from any database
+    pub fn uuids(&self) -> impl Iterator<Item = &Uuid> {
+        self.tasks.keys()
+    }
+
+    /// Every snapshot of the task, in the order the databases were read
+    pub fn snapshots(&self, uuid: &Uuid) -> &[Snapshot] {
+        self.tasks.get(uuid).map_or(&[], Vec::as_slice)
+    }
+
+    /// Merge every task's snapshots with `strategy`. With `sticky_completion`, a task completed or deleted in any
+    /// snapshot stays that way, see `keep_completion`. Snapshots from the devices in `winner_priority` win over later
+    /// ones from other devices, see `precedence`. `progress` is advanced once per task
+    pub fn merge(
+        &self,
+        strategy: MergeStrategy,
+        sticky_completion: bool,
+        winner_priority: &[String],
+        progress: Option<&Progress>,
+    ) -> Result<MergeResult> {
+        let num_tasks = self.tasks.len();
+        let mut merged_tasks = Vec::with_capacity(num_tasks);
+        let mut conflicted_uuids = Vec::new();
+        let mut unique_uuids = 0;
+
+        for (uuid, history) in &self.tasks {
+            // `insert` never leaves a task without snapshots, but there would be nothing to merge if it did
+            if history.is_empty() {
+                warn!(
+                    "Task {} has no snapshots, leaving it out of the merge",
+                    uuid
+                );
+                continue;
+            }
+            let merged = merge_snapshots(history, strategy, sticky_completion, winner_priority)?;
+            if merged.conflicted {
+                conflicted_uuids.push(*merged.task.uuid());
+            }
+            if history.len() == 1 {
+                unique_uuids += 1;
+            }
+
+            merged_tasks.push(merged);
+            if let Some(progress) = progress {
+                progress.inc();
+            }
+        }
+        conflicted_uuids.sort();
+
+        Ok(MergeResult {
+            merged: merged_tasks,
+            conflicted_uuids,
+            unique_uuids,
+        })
+    }
+}
+
+/// Merge the snapshots of one task the same way `History::merge` does. Fails when there are no snapshots, as there
+/// is no task to merge then
+pub fn merge_snapshots(
+    history: &[Snapshot],
+    strategy: MergeStrategy,
+    sticky_completion: bool,
+    winner_priority: &[String],
+) -> Result<Merged> {
+    let Some(winner) = last_writer_wins(history, winner_priority) else {
+        bail!("There are no snapshots of the task to merge");
+    };
+    let mut merged = match strategy {
+        MergeStrategy::LastWriterWins => winner.task.clone(),
+        MergeStrategy::FieldLevel => {
+            let mut merged = field_level(history, winner_priority)?;
+            // A dependency added on one device shouldn't disappear because another device edited the task later
+            merged.set_depends::<_, Uuid>(all_depends(history));
+            merged
+        }
+    };
+
+    if sticky_completion {
+        keep_completion(&mut merged, history, winner_priority);
+    }
+
+    // Annotations are append-only notes, so never lose one just because its snapshot lost
+    merged.set_annotations::<_, Annotation>(all_annotations(history));
+
+    // Devices can disagree about when a task was created (e.g. after a re-import), and the earliest is the closest to
+    // when it really was
+    if let Some(entry) = earliest_entry(history) {
+        *merged.entry_mut() = entry;
+    }
+
+    Ok(Merged {
+        task: merged,
+        winner: winner.clone(),
+        snapshots: history.len(),
+        origins: history
+            .iter()
+            .map(|snapshot| (snapshot.source.clone(), snapshot.path.clone()))
+            .collect(),
+        conflicted: snapshots_differ(history)?,
+    })
+}
+
+/// Why `winner` takes precedence over `other`, going through what `precedence` compares in order
+pub fn why_preferred(
+    winner: &Snapshot,
+    other: &Snapshot,
+    winner_priority: &[String],
+) -> &'static str {
+    let (winner, other) = (
+        precedence(winner, winner_priority),
+        precedence(other, winner_priority),
+    );
+    if winner.0 != other.0 {
+        "its device comes first in winner_priority"
+    } else if winner.1 != other.1 {
+        "it was modified more recently"
+    } else if winner.2 != other.2 {
+        "it was modified at the same second, but has more annotations"
+    } else if winner.3 != other.3 {
+        "it was modified at the same second with as many annotations, and its device ID sorts later"
+    } else {
+        "it is the same in everything the merge compares"
+    }
+}
+
+/// Time a snapshot was last modified, falling back to the entry time
+fn modified_time(task: &Task) -> &Date {
+    match task.modified() {
+        Some(m) => m,
+        None => task.entry(),
+    }
+}
+
+/// `date` as a timestamp, for comparing. taskwarrior's dates are whole seconds in UTC
+fn timestamp(date: &Date) -> Timestamp {
+    let seconds = date.and_utc().timestamp();
+    Timestamp::from_second(seconds).unwrap_or(match seconds < 0 {
+        true => Timestamp::MIN,
+        false => Timestamp::MAX,
+    })
+}
+
+/// Order in which snapshots take precedence: a snapshot from a device earlier in `winner_priority` wins over one from
+/// a device later in it or not in it at all, however old it is. Among devices of the same priority, the most recently
+/// modified wins. Snapshots modified at the same second (common with synced clocks) are told apart by the number of
+/// annotations, since annotations are only ever added, and then by the lexicographically larger device ID, with the
+/// main database losing to every device. So the choice never depends on the order the databases were read in
+fn precedence<'a>(
+    snapshot: &'a Snapshot,
+    winner_priority: &[String],
+) -> (Reverse<usize>, Timestamp, usize, &'a Source) {
+    let device = snapshot.source.device();
+    let priority = winner_priority
+        .iter()
+        .position(|d| d == device)
+        .unwrap_or(winner_priority.len());
+    let modified = timestamp(modified_time(&snapshot.task));
+    let num_annotations = snapshot.task.annotations().map_or(0, |a| a.len());
+    (
+        Reverse(priority),
+        modified,
+        num_annotations,
+        &snapshot.source,
+    )
+}
+
+/// Take the snapshot of the task that takes precedence, usually the one most recently modified, see `precedence`.
+/// None when there are no snapshots at all
+fn last_writer_wins<'a>(
+    history: &'a [Snapshot],
+    winner_priority: &[String],
+) -> Option<&'a Snapshot> {
+    history
+        .iter()
+        .max_by(|a, b| precedence(a, winner_priority).cmp(&precedence(b, winner_priority)))
+}
+
+fn is_done(task: &Task) -> bool {
+    matches!(task.status(), TaskStatus::Completed | TaskStatus::Deleted)
+}
+
+/// If any snapshot completed or deleted the task, but the merge picked a version that is still open, put the
+/// completion back. A device that never saw the completion, but edited the task afterwards, would otherwise resurrect
+/// it. The task is only reopened when the device that completed it has a newer snapshot where it is open again,
+/// because only then is the reopening known to be deliberate
+fn keep_completion(merged: &mut Task, history: &[Snapshot], winner_priority: &[String]) {
+    if is_done(merged) {
+        return;
+    }
+    let Some(done) = history
+        .iter()
+        .filter(|snapshot| is_done(&snapshot.task))
+        .max_by(|a, b| precedence(a, winner_priority).cmp(&precedence(b, winner_priority)))
+    else {
+        return;
+    };
+
+    let reopened = history.iter().any(|snapshot| {
+        snapshot.source == done.source
+            && !is_done(&snapshot.task)
+            && precedence(snapshot, winner_priority) > precedence(done, winner_priority)
+    });
+    if reopened {
+        return;
+    }
+
+    info!(
+        "Keeping task {} {:?}, as {} marked it",
+        merged.uuid(),
+        done.task.status(),
+        done.source
+    );
+    *merged.status_mut() = done.task.status().clone();
+    merged.set_end(done.task.end().cloned());
+}
+
+/// Whether any two snapshots have different attributes, ignoring the ones taskwarrior computes
+fn snapshots_differ(history: &[Snapshot]) -> Result<bool> {
+    let mut first = None;
+    for snapshot in history {
+        let mut fields = fields(&snapshot.task)?;
+        fields.retain(|key, _| !COMPUTED_FIELDS.contains(&key.as_str()));
+        match &first {
+            None => first = Some(fields),
+            Some(first) if *first != fields => return Ok(true),
+            Some(_) => {}
+        }
+    }
+    Ok(false)
+}
+
+/// The earliest creation time of the task across all snapshots
+fn earliest_entry(history: &[Snapshot]) -> Option<Date> {
+    history
+        .iter()
+        .min_by_key(|snapshot| *snapshot.task.entry().deref())
+        .map(|snapshot| snapshot.task.entry().clone())
+}
+
+/// Every distinct dependency from all snapshots, in the order they are first seen
+fn all_depends(history: &[Snapshot]) -> Option<Vec<Uuid>> {
+    let mut depends: Vec<Uuid> = Vec::new();
+    for uuid in history
+        .iter()
+        .filter_map(|snapshot| snapshot.task.depends())
+        .flatten()
+    {
+        if !depends.contains(uuid) {
+            depends.push(*uuid);
+        }
+    }
+
+    if depends.is_empty() {
+        None
+    } else {
+        Some(depends)
+    }
+}
+
+/// Remove dependencies on tasks that are not in `merged`, such as one a field-level merge brought back from an old
+/// snapshot after the task it pointed to was removed. `merged` must hold every task, or valid dependencies are lost
+pub fn prune_dangling_depends(merged: &mut [Merged]) {
+    let uuids: HashSet<Uuid> = merged.iter().map(|m| *m.task.uuid()).collect();
+    for m in merged {
+        let uuid = *m.task.uuid();
+        let Some(depends) = m.task.depends_mut() else {
+            continue;
+        };
+        depends.retain(|dep| {
+            let known = uuids.contains(dep);
+            if !known {
+                info!(
+                    "Dropping the dependency of task {} on {}, which is in none of the databases",
+                    uuid, dep
+                );
+            }
+            known
+        });
+        if depends.is_empty() {
+            m.task.set_depends::<Vec<Uuid>, Uuid>(None);
+        }
+    }
+}
+
+/// Every distinct annotation (by entry time and description) from all snapshots, oldest first
+fn all_annotations(history: &[Snapshot]) -> Option<Vec<Annotation>> {
+    let mut annotations: Vec<Annotation> = Vec::new();
+    for annotation in history
+        .iter()
+        .filter_map(|snapshot| snapshot.task.annotations())
+        .flatten()
+    {
+        if !annotations.contains(annotation) {
+            annotations.push(annotation.clone());
+        }
+    }
+    annotations.sort_by_key(|annotation| *annotation.entry().deref());
+
+    if annotations.is_empty() {
+        None
+    } else {
+        Some(annotations)
+    }
+}
+
+/// A task's attributes as a JSON object, the same as taskwarrior exports them
+fn fields(task: &Task) -> Result<Map<String, Value>> {
+    let Value::Object(fields) = serde_json::to_value(task)? else {
+        bail!("Task {} did not serialize to a JSON object", task.uuid());
+    };
+    Ok(fields)
+}
+
+/// Build a task attribute by attribute, taking each value from the snapshot that takes precedence (usually the most
+/// recently modified one) of those that have it.
+/// List attributes in `UNION_FIELDS` are combined from all snapshots instead.
+/// This works on the serialized task rather than on `Task`'s fields, so user defined attributes, which task_hookrs
+/// flattens into the same JSON object, are merged exactly like the built-in ones.
+fn field_level(history: &[Snapshot], winner_priority: &[String]) -> Result<Task> {
+    // Apply the snapshots in increasing precedence so newer values overwrite older ones
+    let mut snapshots: Vec<&Snapshot> = history.iter().collect();
+    snapshots.sort_by(|a, b| precedence(a, winner_priority).cmp(&precedence(b, winner_priority)));
+
+    let mut merged = Map::new();
+    for snapshot in snapshots {
+        for (key, value) in fields(&snapshot.task)? {
+            match (merged.get_mut(&key), value) {
+                (Some(Value::Array(existing)), Value::Array(new))
+                    if UNION_FIELDS.contains(&key.as_str()) =>
+                {
+                    for item in new {
+                        if !existing.contains(&item) {
+                            existing.push(item);
+                        }
+                    }
+                }
+                (_, value) => {
+                    merged.insert(key, value);
+                }
+            }
+        }
+    }
+
+    Ok(serde_json::from_value(Value::Object(merged))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MAIN_DB_DEVICE;
+    use serde_json::json;
+
+    const UUID: &str = "11111111-1111-1111-1111-111111111111";
+
+    /// A pending task with `fields` on top of the ones every task has
+    fn task(fields: Value) -> Task {
+        let mut task = json!({
+            "uuid": UUID,
+            "status": "pending",
+            "entry": "20240101T000000Z",
+            "modified": "20240102T000000Z",
+            "description": "a task",
+        });
+        task.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(task).unwrap()
+    }
+
+    /// A snapshot of `task` from the conflict copy of `device`, or from the main database for "main"
+    fn snapshot(device: &str, task: Task) -> Snapshot {
+        let source = match device {
+            MAIN_DB_DEVICE => Source::MainDb,
+            device => Source::Conflict {
+                device: device.to_owned(),
+            },
+        };
+        Snapshot {
+            task,
+            source,
+            path: PathBuf::from(format!("{}.sqlite3", device)),
+        }
+    }
+
+    fn merge(history: &[Snapshot], strategy: MergeStrategy) -> Merged {
+        merge_snapshots(history, strategy, false, &[]).unwrap()
+    }
+
+    fn annotation(entry: &str, description: &str) -> Value {
+        json!({"entry": entry, "description": description})
+    }
+
+    #[test]
+    fn annotations_from_every_snapshot_survive_deduplicated() {
+        let shared = annotation("20240101T120000Z", "on both");
+        let history = [
+            snapshot(
+                MAIN_DB_DEVICE,
+                task(json!({"annotations": [shared, annotation("20240101T130000Z", "only main")]})),
+            ),
+            snapshot(
+                "ABCDEFG",
+                task(json!({
+                    "modified": "20240103T000000Z",
+                    "annotations": [annotation("20240101T110000Z", "only the copy"), shared],
+                })),
+            ),
+        ];
+
+        for strategy in [MergeStrategy::LastWriterWins, MergeStrategy::FieldLevel] {
+            let merged = merge(&history, strategy);
+            let descriptions: Vec<&str> = merged
+                .task
+                .annotations()
+                .unwrap()
+                .iter()
+                .map(|annotation| annotation.description().as_str())
+                .collect();
+            assert_eq!(descriptions, ["only the copy", "on both", "only main"]);
+        }
+    }
+
+    /// The device of the snapshot that wins, with the snapshots in either order
+    fn winner(a: &Snapshot, b: &Snapshot, winner_priority: &[String]) -> String {
+        let forward = [a.clone(), b.clone()];
+        let backward = [b.clone(), a.clone()];
+        let forward = last_writer_wins(&forward, winner_priority).unwrap();
+        let backward = last_writer_wins(&backward, winner_priority).unwrap();
+        assert_eq!(forward.path, backward.path, "depends on the order read");
+        forward.source.device().to_owned()
+    }
+
+    #[test]
+    fn equal_modified_times_prefer_more_annotations() {
+        let annotated = snapshot(
+            "AAAAAAA",
+            task(json!({"annotations": [annotation("20240101T120000Z", "note")]})),
+        );
+        let plain = snapshot("ZZZZZZZ", task(json!({})));
+        assert_eq!(winner(&annotated, &plain, &[]), "AAAAAAA");
+    }
+
+    #[test]
+    fn equal_modified_times_prefer_the_larger_device_id() {
+        let a = snapshot("AAAAAAA", task(json!({"description": "from a"})));
+        let b = snapshot("BBBBBBB", task(json!({"description": "from b"})));
+        assert_eq!(winner(&a, &b, &[]), "BBBBBBB");
+        let main = snapshot(MAIN_DB_DEVICE, task(json!({"description": "from main"})));
+        assert_eq!(winner(&a, &main, &[]), "AAAAAAA");
+    }
+
+    #[test]
+    fn field_level_keeps_udas_from_every_snapshot() {
+        let history = [
+            snapshot(
+                MAIN_DB_DEVICE,
+                task(json!({"estimate": "2h", "jira": "PROJ-1"})),
+            ),
+            snapshot(
+                "ABCDEFG",
+                task(json!({
+                    "modified": "20240103T000000Z",
+                    "estimate": "3h",
+                    "reviewer": "me",
+                })),
+            ),
+        ];
+        let merged = serde_json::to_value(merge(&history, MergeStrategy::FieldLevel).task).unwrap();
+        // Set on both: the newer snapshot's value. Set on one only: kept, whichever snapshot has it
+        assert_eq!(merged["estimate"], "3h");
+        assert_eq!(merged["jira"], "PROJ-1");
+        assert_eq!(merged["reviewer"], "me");
+    }
+
+    #[test]
+    fn empty_history_is_an_error() {
+        for strategy in [MergeStrategy::LastWriterWins, MergeStrategy::FieldLevel] {
+            assert!(merge_snapshots(&[], strategy, true, &[]).is_err());
+        }
+    }
+
+    #[test]
+    fn task_without_snapshots_is_left_out() {
+        let mut history = History::new();
+        history.insert(task(json!({})), &Source::MainDb, Path::new("main.sqlite3"));
+        history.tasks.insert(Uuid::nil(), Vec::new());
+
+        let result = history
+            .merge(MergeStrategy::LastWriterWins, false, &[], None)
+            .unwrap();
+        assert_eq!(result.merged.len(), 1);
+        assert_eq!(result.merged[0].task.uuid().to_string(), UUID);
+    }
+
+    #[test]
+    fn earliest_entry_is_kept() {
+        let history = [
+            snapshot(MAIN_DB_DEVICE, task(json!({"entry": "20231201T080000Z"}))),
+            snapshot(
+                "ABCDEFG",
+                task(json!({"entry": "20240101T000000Z", "modified": "20240103T000000Z"})),
+            ),
+        ];
+        assert_eq!(
+            earliest_entry(&history).unwrap().to_string(),
+            "2023-12-01 08:00:00"
+        );
+        for strategy in [MergeStrategy::LastWriterWins, MergeStrategy::FieldLevel] {
+            let merged = merge(&history, strategy);
+            // The newer snapshot still wins everything else
+            assert_eq!(merged.winner.source.device(), "ABCDEFG");
+            assert_eq!(merged.task.entry().to_string(), "2023-12-01 08:00:00");
+        }
+    }
+
+    #[test]
+    fn winner_priority_overrides_modified_times() {
+        let priority = ["SERVERX".to_owned(), "LAPTOPX".to_owned()];
+        let old = |device| snapshot(device, task(json!({})));
+        let new = |device| snapshot(device, task(json!({"modified": "20240105T000000Z"})));
+
+        assert_eq!(
+            winner(&old("SERVERX"), &new("PHONEXX"), &priority),
+            "SERVERX"
+        );
+        assert_eq!(
+            winner(&old("SERVERX"), &new("LAPTOPX"), &priority),
+            "SERVERX"
+        );
+        assert_eq!(
+            winner(&old("LAPTOPX"), &new(MAIN_DB_DEVICE), &priority),
+            "LAPTOPX"
+        );
+    }
+
+    #[test]
+    fn equal_priorities_fall_back_to_modified_times() {
+        let priority = ["SERVERX".to_owned()];
+        let old = snapshot("PHONEXX", task(json!({})));
+        let new = snapshot("TABLETX", task(json!({"modified": "20240105T000000Z"})));
+        assert_eq!(winner(&old, &new, &priority), "TABLETX");
+        assert_eq!(winner(&old, &new, &[]), "TABLETX");
+    }
+
+    #[test]
+    fn depends_are_combined_and_dangling_ones_dropped() {
+        let kept = "22222222-2222-2222-2222-222222222222";
+        let dangling = "33333333-3333-3333-3333-333333333333";
+        let history = [
+            snapshot(MAIN_DB_DEVICE, task(json!({"depends": [kept]}))),
+            snapshot(
+                "ABCDEFG",
+                task(json!({"modified": "20240103T000000Z", "depends": [dangling]})),
+            ),
+        ];
+        let union: Vec<String> = all_depends(&history)
+            .unwrap()
+            .iter()
+            .map(Uuid::to_string)
+            .collect();
+        assert_eq!(union, [kept, dangling]);
+
+        let other = task(json!({"uuid": kept, "description": "depended on"}));
+        let mut merged = vec![
+            merge(&history, MergeStrategy::FieldLevel),
+            merge(
+                &[snapshot(MAIN_DB_DEVICE, other)],
+                MergeStrategy::FieldLevel,
+            ),
+        ];
+        prune_dangling_depends(&mut merged);
+        let depends: Vec<String> = merged[0]
+            .task
+            .depends()
+            .unwrap()
+            .iter()
+            .map(Uuid::to_string)
+            .collect();
+        assert_eq!(depends, [kept]);
+        assert!(merged[1].task.depends().is_none());
+    }
+}
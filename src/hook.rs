@@ -0,0 +1,45 @@
+use std::path::Path;
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+/// Run the user's `post_resolve_hook` after a merge was applied. It is called with the backup dir (empty without a
+/// backup) and the number of conflict files as arguments, which are also in `SYNCTHING_TASK_RESOLVE_BACKUP_DIR` and
+/// `SYNCTHING_TASK_RESOLVE_CONFLICTS`, and with the task dir in `SYNCTHING_TASK_RESOLVE_TASK_DIR`.
+/// The merge is already done, so a failing hook is only logged
+pub fn run(hook: &Path, task_dir: &Path, backup_dir: Option<&Path>, num_conflicts: usize) {
+    let backup_dir = backup_dir.map(Path::as_os_str).unwrap_or_default();
+    let num_conflicts = num_conflicts.to_string();
+
+    info!("Running post-resolve hook {}", hook.display());
+    let output = Command::new(hook)
+        .arg(backup_dir)
+        .arg(&num_conflicts)
+        .env("SYNCTHING_TASK_RESOLVE_TASK_DIR", task_dir)
+        .env("SYNCTHING_TASK_RESOLVE_BACKUP_DIR", backup_dir)
+        .env("SYNCTHING_TASK_RESOLVE_CONFLICTS", &num_conflicts)
+        .output();
+
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Unable to run post-resolve hook {}: {}", hook.display(), e);
+            return;
+        }
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.trim().is_empty() {
+        debug!("hook stdout: {}", stdout.trim());
+    }
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        warn!(
+            "Post-resolve hook {} failed ({}){}",
+            hook.display(),
+            output.status,
+            match stderr.trim() {
+                "" => String::new(),
+                stderr => format!(": {}", stderr),
+            }
+        );
+    }
+}
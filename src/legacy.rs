@@ -0,0 +1,267 @@
+//! Conflict copies of taskwarrior 2.x's plain text databases: `pending.data`, `completed.data`, and `backlog.data`.
+//!
+//! Each line of `pending.data` and `completed.data` is one task, e.g. `[description:"foo" status:"pending" uuid:"…"]`.
+//! Both files are merged together by UUID, since completing a task moves it from one to the other, and every task is
+//! written back to the file its status belongs in. `backlog.data` holds changes not yet sent to a taskserver, which
+//! are combined without duplicates.
+
+use crate::backup::Backup;
+use crate::fsutil::{self, Retry};
+use crate::report;
+use crate::signal;
+use crate::{Conflict, Source};
+use color_eyre::{eyre::WrapErr, Result};
+use jiff::civil::DateTime;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+pub const PATTERN: &str = r"^(?<file>pending|completed|backlog)\.sync-conflict-(?<timestamp>\d{8}-\d{6})-(?<device>[A-Z0-9]{7})\.data$";
+
+const TASK_FILES: &[&str] = &["pending.data", "completed.data"];
+const BACKLOG_FILE: &str = "backlog.data";
+
+static UUID_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\buuid:"([^"]*)""#).unwrap());
+static MODIFIED_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\bmodified:"(\d+)""#).unwrap());
+static ENTRY_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\bentry:"(\d+)""#).unwrap());
+static STATUS_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\bstatus:"([^"]*)""#).unwrap());
+
+/// The merged contents of the legacy data files
+#[derive(Debug)]
+pub struct LegacyMerge {
+    pending: Vec<String>,
+    completed: Vec<String>,
+    backlog: Vec<String>,
+
+    /// Number of tasks written back
+    pub merged_tasks: usize,
+
+    /// Number of tasks whose lines differed between files
+    pub conflicted_tasks: usize,
+}
+
+/// Scan the task dir for syncthing conflict copies of the legacy data files
+pub fn detect_conflicts(task_dir: &Path, date_format: &str) -> Result<Vec<Conflict>> {
+    let re = Regex::new(PATTERN).unwrap();
+    let mut conflicts = Vec::new();
+    for entry in fs::read_dir(task_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        let Some(caps) = re.captures(&name) else {
+            continue;
+        };
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let timestamp =
+            DateTime::strptime(date_format, &caps["timestamp"]).wrap_err_with(|| {
+                format!(
+                    "Unable to parse timestamp of {} with syncthing_date_format '{}'",
+                    path.display(),
+                    date_format
+                )
+            })?;
+        conflicts.push(Conflict {
+            path,
+            source: Source::Conflict {
+                device: caps["device"].to_owned(),
+            },
+            timestamp,
+        });
+    }
+    Ok(conflicts)
+}
+
+/// Merge the main data files in `task_dir` with their conflict copies. When a task differs between files, the line
+/// with the newest `modified` (or `entry`) time wins
+pub fn merge(task_dir: &Path, conflicts: &[Conflict]) -> Result<LegacyMerge> {
+    // Main files first, then conflicts oldest first, so on equal modified times the newer file wins
+    let mut conflicts: Vec<&Conflict> = conflicts.iter().collect();
+    conflicts.sort_by_key(|conflict| conflict.timestamp);
+    let is_backlog = |path: &Path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("backlog."))
+    };
+
+    let mut task_sources: Vec<PathBuf> = TASK_FILES.iter().map(|f| task_dir.join(f)).collect();
+    let mut backlog_sources = vec![task_dir.join(BACKLOG_FILE)];
+    for conflict in conflicts {
+        if is_backlog(&conflict.path) {
+            backlog_sources.push(conflict.path.clone());
+        } else {
+            task_sources.push(conflict.path.clone());
+        }
+    }
+
+    // UUIDs in the order they were first seen, so unchanged files keep their line order
+    let mut order: Vec<String> = Vec::new();
+    let mut tasks: HashMap<String, (u64, String)> = HashMap::new();
+    let mut conflicted: Vec<String> = Vec::new();
+    for source in &task_sources {
+        for line in read_lines(source)? {
+            let Some(uuid) = UUID_RE.captures(&line).map(|caps| caps[1].to_owned()) else {
+                warn!(
+                    "Skipping line without a uuid in {}: {}",
+                    source.display(),
+                    line
+                );
+                continue;
+            };
+            let modified = modified_time(&line);
+            match tasks.get_mut(&uuid) {
+                None => {
+                    order.push(uuid.clone());
+                    tasks.insert(uuid, (modified, line));
+                }
+                Some((saved_modified, saved_line)) => {
+                    if *saved_line != line && !conflicted.contains(&uuid) {
+                        conflicted.push(uuid);
+                    }
+                    if modified >= *saved_modified {
+                        *saved_modified = modified;
+                        *saved_line = line;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut pending = Vec::new();
+    let mut completed = Vec::new();
+    for uuid in &order {
+        let (_, line) = tasks.remove(uuid).expect("every uuid in order has a task");
+        let status = STATUS_RE.captures(&line).map(|caps| caps[1].to_owned());
+        match status.as_deref() {
+            Some("completed") | Some("deleted") => completed.push(line),
+            _ => pending.push(line),
+        }
+    }
+
+    let mut backlog: Vec<String> = Vec::new();
+    for source in &backlog_sources {
+        for line in read_lines(source)? {
+            if !backlog.contains(&line) {
+                backlog.push(line);
+            }
+        }
+    }
+
+    Ok(LegacyMerge {
+        merged_tasks: pending.len() + completed.len(),
+        conflicted_tasks: conflicted.len(),
+        pending,
+        completed,
+        backlog,
+    })
+}
+
+/// Write the merged data files into `dir`, leaving the task dir as it is
+pub fn write_to(dir: &Path, merged: &LegacyMerge) -> Result<()> {
+    for (file, lines) in TASK_FILES.iter().chain([&BACKLOG_FILE]).zip([
+        &merged.pending,
+        &merged.completed,
+        &merged.backlog,
+    ]) {
+        write_lines(&dir.join(file), lines)?;
+    }
+    Ok(())
+}
+
+/// Back up the data files and their conflict copies to `action_history_dir` (if given), write the merged files, and
+/// remove the conflict copies if `delete_conflicts`
+pub fn apply(
+    task_dir: &Path,
+    conflicts: &[Conflict],
+    merged: &LegacyMerge,
+    backup: Option<&Backup>,
+    retry: &Retry,
+    delete_conflicts: bool,
+) -> Result<()> {
+    let main_files: Vec<PathBuf> = TASK_FILES
+        .iter()
+        .chain([&BACKLOG_FILE])
+        .map(|f| task_dir.join(f))
+        .collect();
+
+    let deferred = signal::defer();
+    if let Some(backup) = backup {
+        let files: Vec<&Path> = main_files
+            .iter()
+            .filter(|path| path.is_file())
+            .chain(conflicts.iter().map(|conflict| &conflict.path))
+            .map(PathBuf::as_path)
+            .collect();
+        let checksums = retry.run("Backing up the legacy data files", || {
+            backup.save("legacy", &files)
+        })?;
+        retry.run("Recording the checksums of the legacy data files", || {
+            report::add_checksums(&backup.dir, &checksums, None)
+        })?;
+    }
+
+    // The data files are written in place, so once the first is written all of them have to be
+    deferred.check("writing the legacy data files")?;
+    for (path, lines) in
+        main_files
+            .iter()
+            .zip([&merged.pending, &merged.completed, &merged.backlog])
+    {
+        retry.run(&format!("Writing {}", path.display()), || {
+            write_lines(path, lines)
+        })?;
+    }
+    for conflict in conflicts.iter().filter(|_| delete_conflicts) {
+        retry.run(&format!("Removing {}", conflict.path.display()), || {
+            Ok(fs::remove_file(&conflict.path)?)
+        })?;
+    }
+    info!(
+        "Merged {} legacy tasks from {} conflict files",
+        merged.merged_tasks,
+        conflicts.len()
+    );
+
+    Ok(())
+}
+
+/// Time the task on `line` was last modified, in seconds since the epoch, falling back to the entry time
+fn modified_time(line: &str) -> u64 {
+    MODIFIED_RE
+        .captures(line)
+        .or_else(|| ENTRY_RE.captures(line))
+        .and_then(|caps| caps[1].parse().ok())
+        .unwrap_or(0)
+}
+
+/// Non-empty lines of the file at `path`, or none if it doesn't exist
+fn read_lines(path: &Path) -> Result<Vec<String>> {
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let contents =
+        fs::read_to_string(path).wrap_err_with(|| format!("Unable to read {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Replace the file at `path` with `lines`, so taskwarrior never sees it half-written
+fn write_lines(path: &Path, lines: &[String]) -> Result<()> {
+    let mut contents = String::new();
+    for line in lines {
+        contents.push_str(line);
+        contents.push('\n');
+    }
+    let tmp = fsutil::tmp_sibling(path);
+    fs::write(&tmp, contents).wrap_err_with(|| format!("Unable to write {}", tmp.display()))?;
+    fsutil::replace_atomically(&tmp, path)
+}
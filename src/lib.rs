@@ -0,0 +1,672 @@
+//! Merge taskwarrior (taskchampion.sqlite3) databases that syncthing left behind as conflict copies.
+//!
+//! The `syncthing-task-resolve` binary is a thin wrapper around this library: [`detect_conflicts`] finds the conflict
+//! copies in a task dir, [`resolve`] merges their tasks, and [`apply`] writes the result back. [`run::resolve`] does a
+//! whole run the way the command line does, and the other subcommands live in modules of their own (e.g. [`restore`],
+//! [`doctor`], [`watch`]).
+
+use color_eyre::{eyre::bail, eyre::WrapErr, Result};
+use jiff::civil::DateTime;
+use serde::{Serialize, Serializer};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::thread;
+use task_hookrs::task::Task;
+use tempfile::TempDir;
+use tracing::{debug, info_span, warn, Span};
+use uuid::Uuid;
+
+pub mod audit;
+pub mod backup;
+pub mod config;
+pub mod diff;
+pub mod doctor;
+pub mod explain;
+pub mod fsutil;
+pub mod glob;
+pub mod history;
+pub mod hook;
+pub mod legacy;
+pub mod lock;
+pub mod loops;
+pub mod merge_files;
+pub mod metrics;
+pub mod native;
+pub mod notify;
+pub mod on_launch;
+pub mod progress;
+pub mod prompt;
+pub mod report;
+pub mod resolved;
+pub mod restore;
+pub mod run;
+pub mod sha256;
+pub mod signal;
+pub mod target;
+pub mod taskwarrior;
+pub mod temp;
+pub mod watch;
+
+use backup::Backup;
+use config::{Backend, Config, MergeStrategy};
+use fsutil::Retry;
+use history::{History, MergeResult};
+use progress::Progress;
+use report::Report;
+
+pub const THIS_BIN_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// File name of the main taskchampion database in a task dir
+pub const MAIN_DB_NAME: &str = "taskchampion.sqlite3";
+
+/// Every SQLite database file starts with this
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// What the main database is called where a device ID would otherwise go, e.g. in reports and JSON output
+pub const MAIN_DB_DEVICE: &str = "main";
+
+/// Where a database to merge came from. The main database orders before every device, so it loses ties against them
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Source {
+    /// The task dir's own database
+    MainDb,
+
+    /// A syncthing conflict copy
+    Conflict {
+        /// ID of the device the copy came from, as syncthing put it in the file name
+        device: String,
+    },
+}
+
+impl Source {
+    /// The device ID, or `MAIN_DB_DEVICE` for the main database
+    pub fn device(&self) -> &str {
+        match self {
+            Source::MainDb => MAIN_DB_DEVICE,
+            Source::Conflict { device } => device,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::MainDb => f.write_str("the main database"),
+            Source::Conflict { device } => write!(f, "device {}", device),
+        }
+    }
+}
+
+impl Serialize for Source {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.device())
+    }
+}
+
+/// A database to merge: a syncthing conflict copy, or the main database itself
+#[derive(Debug, Clone, Serialize)]
+pub struct Conflict {
+    pub path: PathBuf,
+
+    #[serde(rename = "device")]
+    pub source: Source,
+
+    /// When syncthing detected the conflict
+    pub timestamp: DateTime,
+}
+
+/// Options for [`resolve`]
+#[derive(Debug)]
+pub struct ResolveOptions<'a> {
+    /// Taskwarrior binary used to read the databases
+    pub task_bin: &'a Path,
+    pub read_backend: Backend,
+    pub merge_strategy: MergeStrategy,
+
+    /// Never let a snapshot that is still open undo a completion or deletion from another device
+    pub sticky_completion: bool,
+
+    /// Device IDs whose snapshots win over other devices' regardless of when they were modified, highest priority first
+    pub winner_priority: &'a [String],
+
+    /// Only read and merge the tasks matching this taskwarrior filter (e.g. `project:work`). Needs the cli backend
+    pub task_filter: Option<&'a str>,
+
+    /// Where to copy the databases to while reading them, instead of the system's temp dir
+    pub temp_dir: Option<&'a Path>,
+
+    /// How many databases to read at once, one per CPU when None. 1 reads them one after the other
+    pub parallelism: Option<NonZeroUsize>,
+
+    /// Draw progress bars on stderr while reading the databases and merging the tasks
+    pub progress: bool,
+
+    /// Fail unless the merge produced exactly one task for every task read from the databases
+    pub verify: bool,
+}
+
+/// Options for [`apply`]
+#[derive(Debug)]
+pub struct ApplyOptions<'a> {
+    /// Taskwarrior binary used to write the merged database
+    pub task_bin: &'a Path,
+    pub write_backend: Backend,
+
+    /// How to retry backing up, removing, and replacing files when that fails with a transient error
+    pub retry: Retry,
+
+    /// Where to write the merged database before it replaces the main one, instead of the system's temp dir
+    pub temp_dir: Option<&'a Path>,
+
+    /// Remove the conflict databases after backing them up, rather than leaving them on disk
+    pub delete_conflicts: bool,
+}
+
+/// Scan the task dir for syncthing conflict copies of the taskchampion database, using the config's file name
+/// pattern and date format
+pub fn detect_conflicts(task_dir: &Path, config: &Config) -> Result<Vec<Conflict>> {
+    let re = config.conflict_regex()?;
+    let date_format = config.syncthing_date_format();
+
+    let mut conflicts = Vec::new();
+    let entries = fs::read_dir(task_dir)
+        .wrap_err_with(|| format!("Unable to read task dir {}", task_dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let Ok(name) = entry.file_name().into_string() else {
+            warn!("Skipping file with non-UTF-8 name: {}", path.display());
+            continue;
+        };
+        let ttype = entry.file_type()?;
+        if ttype.is_file() {
+            if let Some(caps) = re.captures(&name) {
+                // `conflict_regex` checks that the groups exist, but an optional group can still match nothing
+                let Some(timestamp_str) = caps.name("timestamp").or_else(|| caps.get(1)) else {
+                    bail!(
+                        "{} matches conflict_pattern, but no timestamp was captured from it (a group named 'timestamp', or group 1)",
+                        path.display()
+                    );
+                };
+                let timestamp_str = timestamp_str.as_str();
+                let timestamp =
+                    DateTime::strptime(date_format, timestamp_str).wrap_err_with(|| {
+                        format!(
+                            "Unable to parse timestamp '{}' of {} with syncthing_date_format '{}'",
+                            timestamp_str,
+                            path.display(),
+                            date_format
+                        )
+                    })?;
+                let Some(device) = caps.name("device").or_else(|| caps.get(2)) else {
+                    bail!(
+                        "{} matches conflict_pattern, but no device was captured from it (a group named 'device', or group 2)",
+                        path.display()
+                    );
+                };
+                let device = device.as_str().to_owned();
+                conflicts.push(Conflict {
+                    path,
+                    source: Source::Conflict { device },
+                    timestamp,
+                });
+            }
+        }
+    }
+    Ok(conflicts)
+}
+
+/// Read every database in `conflicts` and merge their tasks
+pub fn resolve(conflicts: &[Conflict], opts: &ResolveOptions) -> Result<MergeResult> {
+    let hist = read_history(conflicts, opts)?;
+    let read_uuids: HashSet<Uuid> = hist.uuids().copied().collect();
+
+    // Sort out history conflicts
+    let merging = opts
+        .progress
+        .then(|| Progress::new("Merging tasks", hist.len()));
+    let mut result = hist.merge(
+        opts.merge_strategy,
+        opts.sticky_completion,
+        opts.winner_priority,
+        merging.as_ref(),
+    )?;
+    drop(merging);
+    // Only a merge of every task can tell which dependencies point nowhere
+    if opts.merge_strategy == MergeStrategy::FieldLevel && opts.task_filter.is_none() {
+        history::prune_dangling_depends(&mut result.merged);
+    }
+
+    if opts.verify {
+        verify(&read_uuids, &result)?;
+    }
+    Ok(result)
+}
+
+/// Read every database in `conflicts` into the snapshots of each task, without merging them
+pub fn read_history(conflicts: &[Conflict], opts: &ResolveOptions) -> Result<History> {
+    // Read every database. Each is copied to its own tempdir, so this can happen in parallel
+    let reading = opts
+        .progress
+        .then(|| Progress::new("Reading databases", conflicts.len()));
+    let snapshots = read_databases(opts, conflicts, reading.as_ref())?;
+    drop(reading);
+
+    let mut hist = History::new();
+    for (conflict, tasks) in conflicts.iter().zip(snapshots) {
+        debug!("Timestamp: {}", conflict.timestamp);
+        for task in tasks {
+            hist.insert(task, &conflict.source, &conflict.path);
+        }
+    }
+    Ok(hist)
+}
+
+/// Check that the merge produced every task that was read exactly once, so a bug in the merge can never silently drop
+/// or duplicate a task in the main db
+fn verify(read_uuids: &HashSet<Uuid>, result: &MergeResult) -> Result<()> {
+    let mut merged_uuids = HashSet::new();
+    let mut duplicated: Vec<Uuid> = Vec::new();
+    for m in &result.merged {
+        if !merged_uuids.insert(*m.task.uuid()) {
+            duplicated.push(*m.task.uuid());
+        }
+    }
+    let mut missing: Vec<&Uuid> = read_uuids.difference(&merged_uuids).collect();
+    let mut unexpected: Vec<&Uuid> = merged_uuids.difference(read_uuids).collect();
+    if missing.is_empty() && unexpected.is_empty() && duplicated.is_empty() {
+        return Ok(());
+    }
+
+    missing.sort();
+    unexpected.sort();
+    duplicated.sort();
+    let list = |uuids: Vec<String>| match uuids.is_empty() {
+        true => String::from("none"),
+        false => uuids.join(", "),
+    };
+    bail!(
+        "The merge produced {} tasks, but {} distinct tasks were read, nothing was changed. This is a bug, please report \
+         it. Pass --no-verify to write the merge result anyway\n  missing: {}\n  not read from any database: {}\n  \
+         duplicated: {}",
+        result.merged.len(),
+        read_uuids.len(),
+        list(missing.iter().map(ToString::to_string).collect()),
+        list(unexpected.iter().map(ToString::to_string).collect()),
+        list(duplicated.iter().map(ToString::to_string).collect())
+    );
+}
+
+/// Check for the SQLite header magic at the start of the file
+pub fn is_sqlite_db(path: &Path) -> Result<bool> {
+    let mut header = [0; SQLITE_HEADER.len()];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut header) {
+        Ok(()) => Ok(header == *SQLITE_HEADER),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// SQLite's write-ahead log for the database at `db_path`
+pub fn wal_path(db_path: &Path) -> PathBuf {
+    let mut name = db_path.file_name().map(OsString::from).unwrap_or_default();
+    name.push("-wal");
+    db_path.with_file_name(name)
+}
+
+/// Read the tasks from each database in `conflicts`, returning them in the same order as `conflicts`. They are split
+/// into one contiguous chunk per worker, each read on a scoped thread
+// TODO: read them on a rayon pool sized by `parallelism` once rayon can be added as a dependency
+fn read_databases(
+    opts: &ResolveOptions,
+    conflicts: &[Conflict],
+    progress: Option<&Progress>,
+) -> Result<Vec<Vec<Task>>> {
+    if conflicts.is_empty() {
+        return Ok(Vec::new());
+    }
+    let num_workers = match opts.parallelism {
+        Some(n) => n.get(),
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let chunk_size = conflicts.len().div_ceil(num_workers);
+    // Spans don't follow the work onto other threads by themselves
+    let parent = Span::current();
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = conflicts
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let parent = &parent;
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|conflict| {
+                            let _span = info_span!(
+                                parent: parent,
+                                "conflict",
+                                path = %conflict.path.display(),
+                                device = conflict.source.device()
+                            )
+                            .entered();
+                            let tasks = read_database(opts, &conflict.path);
+                            if let Some(progress) = progress {
+                                progress.inc();
+                            }
+                            tasks
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("database reader thread panicked"))
+            .collect()
+    })
+}
+
+/// Read the tasks (only those matching the `task_filter`, if any) from a copy of the database at `path`, so neither
+/// taskwarrior nor taskchampion can modify the original
+pub fn read_database(opts: &ResolveOptions, path: &Path) -> Result<Vec<Task>> {
+    let tmp = scratch_dir(opts.temp_dir)?;
+    let tmp_dir = tmp.path();
+    copy_database(path, tmp_dir)?;
+
+    debug!("DB: {}", path.display());
+    match (opts.read_backend, opts.task_filter) {
+        (Backend::Cli, query) => {
+            taskwarrior::query(opts.task_bin, tmp_dir, query.unwrap_or_default())
+        }
+        (Backend::Native, None) => native::read(tmp_dir),
+        (Backend::Native, Some(_)) => {
+            bail!("Filtering tasks needs taskwarrior, it is not possible with the native backend")
+        }
+    }
+}
+
+/// Copy the database at `path`, and its write-ahead log if it has one, into `dir` as the main database
+pub fn copy_database(path: &Path, dir: &Path) -> Result<()> {
+    let dest = dir.join(MAIN_DB_NAME);
+    fs::copy(path, &dest)
+        .wrap_err_with(|| format!("Unable to copy {} to {}", path.display(), dest.display()))?;
+
+    // SQLite replays the write-ahead log when the copy is opened, so the copy sees everything the original would
+    let wal = wal_path(path);
+    if wal.is_file() {
+        fs::copy(&wal, wal_path(&dest))
+            .wrap_err_with(|| format!("Unable to copy {} to {}", wal.display(), dir.display()))?;
+    }
+    Ok(())
+}
+
+/// A new directory for scratch copies of databases, in `temp_dir` or else the system's temp dir (`$TMPDIR`)
+pub fn scratch_dir(temp_dir: Option<&Path>) -> Result<TempDir> {
+    match temp_dir {
+        Some(dir) => TempDir::new_in(dir).wrap_err_with(|| {
+            format!(
+                "Unable to create a temporary directory in {}",
+                dir.display()
+            )
+        }),
+        None => TempDir::new().wrap_err("Unable to create a temporary directory"),
+    }
+}
+
+/// Import `tasks` into a new database in `dir`, and return its path
+pub fn write_database(opts: &ApplyOptions, tasks: &[Task], dir: &Path) -> Result<PathBuf> {
+    match opts.write_backend {
+        Backend::Cli => taskwarrior::import(opts.task_bin, dir, tasks)?,
+        Backend::Native => native::write(dir, tasks)?,
+    }
+    let db = dir.join(MAIN_DB_NAME);
+    if !is_sqlite_db(&db).unwrap_or(false) {
+        let writer = match opts.write_backend {
+            Backend::Cli => format!("'{} import'", opts.task_bin.display()),
+            Backend::Native => String::from("taskchampion"),
+        };
+        bail!("{} did not produce a database in {}", writer, dir.display());
+    }
+    Ok(db)
+}
+
+/// Import the merged tasks into a fresh database, back up the conflict databases, replace the main db, and remove the
+/// conflict databases. Nothing is backed up or removed unless the import succeeded, nor removed unless the main db was
+/// replaced. Without `backup`, nothing is backed up at all, and
+/// without `opts.delete_conflicts` nothing is removed
+pub fn apply(
+    opts: &ApplyOptions,
+    tasks: &[Task],
+    conflicts: &[Conflict],
+    backup: Option<&Backup>,
+    report: &Report,
+    main_db_path: &Path,
+) -> Result<()> {
+    // A signal from here on is only acted on where stopping leaves the task dir as it was, or once the main db is
+    // replaced
+    let deferred = signal::defer();
+
+    // Save our tasks in a taskchampion database
+    let tmp = scratch_dir(opts.temp_dir)?;
+    let updated_db = write_database(opts, tasks, tmp.path())
+        .wrap_err_with(|| format!("Leaving {} untouched", main_db_path.display()))?;
+    // Recorded so `undo` can tell whether the main db is still the one written here
+    let merged_checksum = sha256::digest_file(&updated_db)
+        .wrap_err_with(|| format!("Unable to read {}", updated_db.display()))?;
+    deferred.check(&format!(
+        "backing up, leaving {} untouched",
+        main_db_path.display()
+    ))?;
+
+    // Only now that the merged tasks are safely in a new database, back up the conflict databases. The main db is
+    // backed up too, but left in place until it is replaced
+    let retry = &opts.retry;
+    if let Some(backup) = backup {
+        let files: Vec<&Path> = conflicts.iter().map(|c| c.path.as_path()).collect();
+        let checksums = retry.run("Backing up the databases", || {
+            backup.save("databases", &files)
+        })?;
+        retry.run("Writing the report", || {
+            report.write(&backup.dir, &checksums, &merged_checksum)
+        })?;
+    }
+    deferred.check(&format!(
+        "replacing {}, leaving it untouched",
+        main_db_path.display()
+    ))?;
+
+    // Replace the main db with the updated tasks. The conflict files are only removed once it is, so a failure here
+    // (e.g. a full disk) never loses their tasks, even without a backup
+    retry.run(&format!("Replacing {}", main_db_path.display()), || {
+        fsutil::replace_atomically(&updated_db, main_db_path)
+    })?;
+
+    // Their tasks are in the main db now. One that can't be removed is only left behind, the next run finds it in
+    // `resolved.toml` or identical to the main db and removes it then
+    for Conflict { path, source, .. } in conflicts {
+        if *source != Source::MainDb && opts.delete_conflicts {
+            let removed = retry.run(&format!("Removing {}", path.display()), || {
+                Ok(fs::remove_file(path)?)
+            });
+            if let Err(e) = removed {
+                warn!("{:#}", e);
+            }
+        }
+    }
+
+    if let Some(name) = signal::received() {
+        warn!(
+            "Received {} while resolving, finished replacing {} first",
+            name,
+            main_db_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    const CONFLICT_NAME: &str = "taskchampion.sync-conflict-20240103-101010-ABCDEFG.sqlite3";
+
+    #[test]
+    fn non_utf8_file_names_are_skipped() {
+        let task_dir = tempfile::tempdir().unwrap();
+        let invalid = OsStr::from_bytes(b"taskchampion.sync-conflict-\xff\xfe.sqlite3");
+        fs::write(task_dir.path().join(invalid), b"").unwrap();
+        fs::write(task_dir.path().join(CONFLICT_NAME), b"").unwrap();
+
+        let conflicts = detect_conflicts(task_dir.path(), &Config::default()).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, task_dir.path().join(CONFLICT_NAME));
+        assert_eq!(conflicts[0].source.device(), "ABCDEFG");
+    }
+
+    #[test]
+    fn only_files_with_the_sqlite_header_are_databases() {
+        let dir = tempfile::tempdir().unwrap();
+        let check = |name: &str, contents: &[u8]| {
+            let path = dir.path().join(name);
+            fs::write(&path, contents).unwrap();
+            is_sqlite_db(&path).unwrap()
+        };
+
+        let mut valid = SQLITE_HEADER.to_vec();
+        valid.extend_from_slice(&[0; 84]);
+        assert!(check("valid", &valid));
+        assert!(!check("empty", b""));
+        assert!(!check("truncated", &SQLITE_HEADER[..8]));
+        assert!(!check(
+            "text",
+            b"[{\"uuid\": \"11111111-1111-1111-1111-111111111111\"}]"
+        ));
+        assert!(is_sqlite_db(&dir.path().join("missing")).is_err());
+    }
+
+    /// `apply` the tasks of a conflict file in `task_dir` to `main_db_path`, without a backup
+    fn apply_without_backup(task_dir: &Path, main_db_path: &Path) -> (Result<()>, PathBuf) {
+        let conflict = Conflict {
+            path: task_dir.join(CONFLICT_NAME),
+            source: Source::Conflict {
+                device: "ABCDEFG".to_owned(),
+            },
+            timestamp: DateTime::default(),
+        };
+        fs::write(&conflict.path, SQLITE_HEADER).unwrap();
+        let task: Task = serde_json::from_value(serde_json::json!({
+            "uuid": "00000000-0000-0000-0000-000000000001",
+            "status": "pending",
+            "entry": "20240101T000000Z",
+            "description": "merged",
+        }))
+        .unwrap();
+        let opts = ApplyOptions {
+            task_bin: Path::new("task"),
+            write_backend: Backend::Native,
+            retry: Retry {
+                retries: 0,
+                delay: std::time::Duration::ZERO,
+            },
+            temp_dir: Some(task_dir),
+            delete_conflicts: true,
+        };
+        let path = conflict.path.clone();
+        let conflicts = [conflict];
+        let report = Report::new(
+            main_db_path,
+            MergeStrategy::default(),
+            None,
+            &conflicts,
+            &[],
+        );
+        let applied = apply(&opts, &[task], &conflicts, None, &report, main_db_path);
+        (applied, path)
+    }
+
+    #[test]
+    fn conflict_files_are_kept_when_the_main_db_cannot_be_replaced() {
+        let task_dir = tempfile::tempdir().unwrap();
+        // A directory that isn't empty can't be renamed over
+        let main_db_path = task_dir.path().join(MAIN_DB_NAME);
+        fs::create_dir(&main_db_path).unwrap();
+        fs::write(main_db_path.join("in the way"), b"").unwrap();
+
+        let (applied, conflict) = apply_without_backup(task_dir.path(), &main_db_path);
+        assert!(applied.is_err());
+        assert!(conflict.is_file());
+    }
+
+    #[test]
+    fn conflict_files_are_removed_once_the_main_db_is_replaced() {
+        let task_dir = tempfile::tempdir().unwrap();
+        let main_db_path = task_dir.path().join(MAIN_DB_NAME);
+        fs::write(&main_db_path, SQLITE_HEADER).unwrap();
+
+        let (applied, conflict) = apply_without_backup(task_dir.path(), &main_db_path);
+        applied.unwrap();
+        assert!(!conflict.exists());
+        assert_eq!(native::read(task_dir.path()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn databases_are_read_in_order_at_any_parallelism() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conflicts = Vec::new();
+        let mut expected = Vec::new();
+        for i in 0..5 {
+            let db_dir = dir.path().join(i.to_string());
+            let task: Task = serde_json::from_value(serde_json::json!({
+                "uuid": format!("00000000-0000-0000-0000-00000000000{}", i),
+                "status": "pending",
+                "entry": "20240101T000000Z",
+                "description": format!("task {}", i),
+            }))
+            .unwrap();
+            fs::create_dir(&db_dir).unwrap();
+            native::write(&db_dir, &[task]).unwrap();
+            conflicts.push(Conflict {
+                path: db_dir.join(MAIN_DB_NAME),
+                source: Source::Conflict {
+                    device: i.to_string(),
+                },
+                timestamp: DateTime::default(),
+            });
+            expected.push(format!("task {}", i));
+        }
+
+        for parallelism in [1, 2, 3, 5, 8] {
+            let opts = ResolveOptions {
+                task_bin: Path::new("task"),
+                read_backend: Backend::Native,
+                merge_strategy: MergeStrategy::default(),
+                sticky_completion: false,
+                winner_priority: &[],
+                task_filter: None,
+                temp_dir: Some(dir.path()),
+                parallelism: NonZeroUsize::new(parallelism),
+                progress: false,
+                verify: true,
+            };
+            let read: Vec<String> = read_databases(&opts, &conflicts, None)
+                .unwrap()
+                .into_iter()
+                .map(|tasks| {
+                    assert_eq!(tasks.len(), 1);
+                    tasks[0].description().clone()
+                })
+                .collect();
+            assert_eq!(read, expected, "with parallelism {}", parallelism);
+        }
+    }
+}
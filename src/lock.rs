@@ -0,0 +1,62 @@
+use color_eyre::{eyre::bail, eyre::WrapErr, Result};
+use std::fs::{self, File, TryLockError};
+use std::path::Path;
+
+const LOCK_FILE_NAME: &str = ".lock";
+
+/// Exclusive lock on the state dir, released when this is dropped (or the process exits)
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Take the lock, failing immediately if another instance already holds it
+    pub fn acquire(state_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(state_dir)?;
+        let path = state_dir.join(LOCK_FILE_NAME);
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .wrap_err_with(|| format!("Unable to open lock file {}", path.display()))?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => bail!(
+                "Another instance of {} is already running (lock held on {})",
+                crate::THIS_BIN_NAME,
+                path.display()
+            ),
+            Err(TryLockError::Error(e)) => {
+                Err(e).wrap_err_with(|| format!("Unable to lock {}", path.display()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_acquire_fails_while_the_first_is_held() {
+        let state_dir = tempfile::tempdir().unwrap();
+        let first = Lock::acquire(state_dir.path()).unwrap();
+
+        let err = Lock::acquire(state_dir.path()).unwrap_err();
+        assert!(err.to_string().contains("already running"), "{}", err);
+
+        drop(first);
+        Lock::acquire(state_dir.path()).unwrap();
+    }
+
+    #[test]
+    fn acquire_creates_the_state_dir() {
+        let parent = tempfile::tempdir().unwrap();
+        let state_dir = parent.path().join("state");
+        let _lock = Lock::acquire(&state_dir).unwrap();
+        assert!(state_dir.join(LOCK_FILE_NAME).is_file());
+    }
+}
@@ -0,0 +1,152 @@
+//! Log output, as text for people or as one JSON object per line for log collectors.
+//!
+//! Both include the spans an event happened in, such as the run and the conflict file being read, so the lines of one
+//! run or one file can be picked out of interleaved output.
+
+use clap::ValueEnum;
+use jiff::Timestamp;
+use serde_json::{Map, Value};
+use std::env;
+use std::fmt;
+use std::io::{self, Write};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::{filter_fn, LevelFilter, Targets};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per event, with its fields and the fields of the spans it happened in
+    Json,
+}
+
+/// Log to stderr at the level picked with `-v`, or as set with `RUST_LOG` (e.g. `debug` or
+/// `syncthing_task_resolve::history=trace,info`)
+pub fn init(verbose: u8, format: LogFormat) {
+    let level = match verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let filter = match env::var("RUST_LOG") {
+        Ok(directives) if !directives.is_empty() => directives.parse().unwrap_or_else(|e| {
+            eprintln!("Ignoring RUST_LOG '{}': {}", directives, e);
+            Targets::new().with_default(level)
+        }),
+        _ => Targets::new().with_default(level),
+    };
+
+    // Spans are always kept, so a warning still says which run and conflict file it is about when info is filtered out
+    let filter = filter_fn(move |metadata| {
+        metadata.is_span() || filter.would_enable(metadata.target(), metadata.level())
+    });
+    let registry = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Text => registry
+            .with(tracing_subscriber::fmt::layer().with_writer(io::stderr))
+            .init(),
+        LogFormat::Json => registry.with(JsonLayer).init(),
+    }
+}
+
+// TODO: use `tracing_subscriber::fmt::layer().json()` once the `json` feature can be turned on, which needs the
+// tracing-serde crate
+
+/// Writes each event to stderr as a JSON object, e.g.
+/// `{"fields":{},"level":"INFO","message":"…","spans":[{"name":"run","run_id":"…"}],"target":"…","timestamp":"…"}`
+struct JsonLayer;
+
+/// The fields of a span, kept until its events are written
+struct SpanFields(Map<String, Value>);
+
+impl<S> Layer<S> for JsonLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(SpanFields(fields)) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(&mut JsonVisitor(fields));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+        let message = fields.remove("message").unwrap_or(Value::Null);
+
+        let mut spans = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                let mut object = Map::new();
+                object.insert("name".to_owned(), Value::from(span.name()));
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    object.extend(fields.clone());
+                }
+                spans.push(Value::Object(object));
+            }
+        }
+
+        let mut line = Map::new();
+        line.insert(
+            "timestamp".to_owned(),
+            Value::from(Timestamp::now().to_string()),
+        );
+        line.insert("level".to_owned(), Value::from(metadata.level().as_str()));
+        line.insert("target".to_owned(), Value::from(metadata.target()));
+        line.insert("message".to_owned(), message);
+        line.insert("fields".to_owned(), Value::Object(fields));
+        line.insert("spans".to_owned(), Value::Array(spans));
+
+        // Logging must never be what fails a run
+        let _ = writeln!(io::stderr().lock(), "{}", Value::Object(line));
+    }
+}
+
+/// Collects the fields of a span or event, keeping numbers and booleans as such
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), Value::from(format!("{:?}", value)));
+    }
+}
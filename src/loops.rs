@@ -0,0 +1,83 @@
+//! Tasks that conflict again every time they are resolved, as when two devices keep overwriting each other's changes.
+//! Resolving only hides such a loop, so how many resolves in a row each task conflicted in is kept in the state dir.
+
+use crate::fsutil;
+use crate::history::Merged;
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+const FILE_NAME: &str = "conflict_streaks.toml";
+
+/// Tasks that conflicted in the last resolve, and in how many resolves in a row before it
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Streaks {
+    #[serde(default)]
+    tasks: BTreeMap<Uuid, Streak>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Streak {
+    /// Resolves in a row the task conflicted in
+    pub runs: u32,
+
+    /// Devices whose snapshots of the task were merged in the last of them
+    pub devices: Vec<String>,
+}
+
+impl Streaks {
+    /// The streaks of the task dir whose state dir is `state_dir`. A missing or broken file starts them over
+    pub fn load(state_dir: &Path) -> Self {
+        let path = state_dir.join(FILE_NAME);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                warn!("Unable to read {}: {}", path.display(), e);
+                return Self::default();
+            }
+        };
+        toml::from_str(&contents).unwrap_or_else(|e| {
+            warn!("Ignoring {}, it is not valid: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    /// Count a resolve that merged `merged`. The tasks that conflicted again continue their streak, and every other
+    /// task's streak is over
+    pub fn record(&mut self, merged: &[Merged]) {
+        let mut tasks = BTreeMap::new();
+        for m in merged.iter().filter(|m| m.conflicted) {
+            let uuid = *m.task.uuid();
+            let runs = self.tasks.get(&uuid).map_or(0, |streak| streak.runs) + 1;
+            let mut devices: Vec<String> = m
+                .origins
+                .iter()
+                .map(|(source, _)| source.device().to_owned())
+                .collect();
+            devices.sort();
+            devices.dedup();
+            tasks.insert(uuid, Streak { runs, devices });
+        }
+        self.tasks = tasks;
+    }
+
+    pub fn get(&self, uuid: &Uuid) -> Option<&Streak> {
+        self.tasks.get(uuid)
+    }
+
+    pub fn save(&self, state_dir: &Path) -> Result<()> {
+        let path = state_dir.join(FILE_NAME);
+        debug!("Writing {}", path.display());
+        fs::create_dir_all(state_dir)?;
+        let tmp = fsutil::tmp_sibling(&path);
+        fs::write(&tmp, toml::to_string_pretty(self)?)
+            .wrap_err_with(|| format!("Unable to write {}", tmp.display()))?;
+        fsutil::replace_atomically(&tmp, &path)
+    }
+}
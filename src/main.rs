@@ -1,289 +1,421 @@
-use clap::Parser;
-use color_eyre::eyre::ContextCompat;
-use color_eyre::{eyre::bail, Result};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueHint};
+use color_eyre::{eyre::bail, eyre::WrapErr, Result};
 use directories::ProjectDirs;
-use jiff::civil::date;
-use jiff::tz::TimeZone;
-use jiff::{civil::DateTime, Zoned};
-use log::{debug, warn};
-use once_cell::sync::Lazy;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{DirEntry, File};
-use std::io::{stderr, stdout, Write};
-use std::ops::Deref;
-use std::path::Path;
+use jiff::{Span, Timestamp};
+use serde::Serialize;
 use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::{env, fs};
-use task_hookrs::task::Task;
-use task_hookrs::tw;
-use taskchampion::TaskData;
-use tempfile::TempDir;
-use uuid::Uuid;
-mod config;
-use config::{Config, DEFAULT_KEEP_NUM};
-
-const THIS_BIN_NAME: &str = env!("CARGO_PKG_NAME");
-const PATTERN: &str = r"^taskchampion\.sync-conflict-(\d{8}-\d{6})-([A-Z0-9]{7})\.sqlite3$";
-const DATE_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
-const SYNCTHING_DATE_FORMAT: &str = "%Y%m%d-%H%M%S";
+use std::process::{self, ExitCode};
+use std::time::Duration;
+mod completions;
+mod logging;
+use completions::Shell;
+use logging::LogFormat;
+use syncthing_task_resolve::backup;
+use syncthing_task_resolve::config::Config;
+use syncthing_task_resolve::run::{self, ResolveArgs};
+use syncthing_task_resolve::target::{self, Target};
+use syncthing_task_resolve::{
+    doctor, merge_files, on_launch, restore, signal, taskwarrior, temp, watch, THIS_BIN_NAME,
+};
+use tracing::info_span;
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0   No conflicts were found (or a subcommand other than resolve succeeded)
+  1   An error occurred
+  10  Conflicts were found and resolved
+  11  Conflicts were found but not applied (--dry-run, --review-out, or the prompt was declined)";
 
 #[derive(Debug, Parser)]
+#[clap(version, about, after_help = EXIT_CODES_HELP)]
 struct Cli {
     /// Path to taskwarrior data directory
-    #[clap(short, long)]
+    #[clap(short, long, global = true, value_hint = ValueHint::DirPath)]
     task_dir: Option<PathBuf>,
 
-    /// Do not actually make changes, only report what would happen
-    #[clap(short, long)]
-    dry_run: bool,
-}
+    /// Path to the config file, instead of the one in the XDG config dir
+    #[clap(short, long, global = true, value_hint = ValueHint::FilePath)]
+    config: Option<PathBuf>,
 
-fn default_task_dir() -> Result<PathBuf> {
-    let data_dir = PathBuf::from(std::env::var("XDG_DATA_HOME")?);
-    let task_dir = data_dir.join("task");
-    Ok(task_dir)
-}
+    /// Directory to keep backups in, instead of the XDG state dir. Overrides `state_dir` from the config
+    #[clap(long, global = true, value_hint = ValueHint::DirPath)]
+    state_dir: Option<PathBuf>,
 
-#[derive(Debug)]
-struct History {
-    tasks: HashMap<Uuid, Vec<Task>>,
-}
-impl History {
-    fn new() -> Self {
-        Self {
-            tasks: HashMap::new(),
-        }
-    }
+    /// Taskwarrior binary to use, instead of looking up 'task' on the $PATH
+    #[clap(long, global = true, value_hint = ValueHint::ExecutablePath)]
+    task_bin: Option<PathBuf>,
 
-    fn insert(&mut self, task: Task) {
-        let key = task.uuid();
+    /// Log more: -v for info, -vv for debug, -vvv for trace. RUST_LOG takes precedence when set
+    #[clap(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
 
-        if let Some(vals) = self.tasks.get_mut(&key) {
-            vals.push(task);
-        } else {
-            self.tasks.insert(*key, vec![task]);
-        }
-    }
+    /// Format of the log messages on stderr. JSON has one object per line, with the run ID and the conflict file each
+    /// message is about as fields
+    #[clap(long, value_enum, global = true, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
 
-    fn merge(&self) -> Vec<Task> {
-        let num_tasks = self.tasks.len();
-        let mut merged_tasks = Vec::with_capacity(num_tasks);
-
-        // Loop over each task, compare the last modified time of the each task's history snapshot and take the one
-        // that was most recently modified
-        for task in self.tasks.values() {
-            let mut history = task.iter();
-
-            // Start with the first task in the history list
-            let mut saved = history.next().unwrap();
-            let mut modified_time = match saved.modified() {
-                Some(m) => m,
-                None => {
-                    // Fall back to the entry time
-                    saved.entry()
-                }
-            };
-            while let Some(next_task) = history.next() {
-                let next_modified_time = match next_task.modified() {
-                    Some(m) => m,
-                    None => {
-                        // Fall back to the entry time
-                        next_task.entry()
-                    }
-                };
-
-                // Deref because taskhook_rs::Date holds a Chrono::NativeDateTime
-                if next_modified_time.deref() > modified_time.deref() {
-                    saved = next_task;
-                    modified_time = next_modified_time;
+    /// Print the configuration in effect, after combining the config file, command line options, and defaults, then
+    /// exit without resolving anything
+    #[clap(long)]
+    dump_config: bool,
+
+    /// Write the default config file if there is none yet, print its path, and exit without resolving anything, so it
+    /// can be edited first
+    #[clap(long)]
+    init: bool,
+
+    /// Remove the temporary directories left behind by runs that were killed, then exit without resolving anything
+    #[clap(long)]
+    cleanup_temp: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Options for the default `resolve` command
+    #[clap(flatten)]
+    resolve: ResolveArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Merge syncthing conflict databases into the main database (default)
+    Resolve(Box<ResolveArgs>),
+
+    /// List detected conflict files without touching anything
+    List,
+
+    /// Merge two databases into a new one, the same way conflict files are merged, without looking at the task dir
+    MergeFiles {
+        #[clap(value_hint = ValueHint::FilePath)]
+        a: PathBuf,
+
+        #[clap(value_hint = ValueHint::FilePath)]
+        b: PathBuf,
+
+        /// Where to write the merged database. It must not exist yet
+        #[clap(long, value_name = "OUT", value_hint = ValueHint::FilePath)]
+        out: PathBuf,
+    },
+
+    /// Copy a backup from the state directory back into the task directory
+    Restore {
+        /// Name of the backup directory to restore (e.g. 2024-01-02_03-04-05+0000)
+        timestamp: String,
+    },
+
+    /// Print a shell completion script
+    Completions {
+        #[clap(value_enum)]
+        shell: Shell,
+    },
+
+    /// Check that everything needed to resolve conflicts is in place, and print what was found
+    Doctor,
+
+    /// Remove the backups from the state directory, and report how much space that freed
+    Purge {
+        /// Only remove backups older than this (e.g. 30d, or "2 weeks")
+        #[clap(long, value_name = "DURATION")]
+        older_than: Option<Span>,
+
+        /// Don't ask for confirmation first. Required when stdin is not a terminal
+        #[clap(short, long)]
+        yes: bool,
+    },
+
+    /// Stay running, and resolve whenever new conflict files show up (after resolving the ones there already are)
+    Watch {
+        /// How long the conflict files have to stay unchanged before resolving, so syncthing can finish writing them
+        #[clap(long, value_name = "DURATION", default_value = "5s")]
+        debounce: Span,
+
+        #[clap(flatten)]
+        resolve: Box<ResolveArgs>,
+    },
+
+    /// Put the main database from the most recent backup back in place, undoing the last resolve
+    Undo {
+        /// Undo even if the main database has changed since the backup was made
+        #[clap(short, long)]
+        force: bool,
+    },
+}
+
+impl Cli {
+    /// Parse the command line, rejecting options for the default `resolve` command when a subcommand is also given
+    fn parse_checked() -> Self {
+        let mut cmd = Self::command();
+        let matches = cmd.get_matches_mut();
+        let cli = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+        if let Some(command) = matches.subcommand_name() {
+            let resolve_args = ResolveArgs::augment_args(clap::Command::new(THIS_BIN_NAME));
+            for arg in resolve_args.get_arguments() {
+                let id = arg.get_id().as_str();
+                if matches.value_source(id) == Some(ValueSource::CommandLine) {
+                    let flag = arg.get_long().unwrap_or(id);
+                    let msg = format!(
+                        "'--{}' cannot be used before the '{}' subcommand",
+                        flag, command
+                    );
+                    cmd.error(ErrorKind::ArgumentConflict, msg).exit();
                 }
             }
-
-            merged_tasks.push(saved.clone());
         }
 
-        merged_tasks
+        cli
     }
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     color_eyre::install()?;
-    env_logger::init();
-    let args = Cli::parse();
-
-    let Some(proj_dirs) = ProjectDirs::from("", "", THIS_BIN_NAME) else {
-        bail!("Unable to get XDG project dirs");
-    };
-    let config_dir = proj_dirs.config_dir();
-    let config_file = config_dir.join("config.toml");
-    let config = if config_file.is_file() {
-        let contents = fs::read_to_string(&config_file)?;
-        toml::from_str(&contents)?
-    } else {
-        let config = Config::default();
-        let contents = toml::to_string_pretty(&config)?;
-        let parent = config_file.parent().unwrap();
-        fs::create_dir_all(parent)?;
-        fs::write(&config_file, contents)?;
-        config
-    };
-
-    let Some(state_dir) = proj_dirs.state_dir() else {
-        bail!("Unable to get XDG state dir")
+    let mut args = Cli::parse_checked();
+    logging::init(args.verbose, args.log_format);
+    signal::install();
+    // Every message of this run carries its ID, so it can be told apart from other runs logging to the same place
+    let run_id = format!("{}-{}", Timestamp::now().as_second(), process::id());
+    let _run = info_span!("run", run_id = %run_id).entered();
+
+    let Some(hook_args) = resolve_args(&args).and_then(|resolve| resolve.hook.clone()) else {
+        return run(args);
     };
+    // Taskwarrior doesn't run the command when its on-launch hook fails, and a problem resolving conflicts shouldn't
+    // lock anyone out of their tasks. What the hook prints is shown as feedback
+    let result = on_launch::Launch::parse(&hook_args).and_then(|launch| {
+        args.task_dir = args.task_dir.take().or(launch.data);
+        run(args)
+    });
+    if let Err(e) = result {
+        println!("{}: {:#}", THIS_BIN_NAME, e);
+    }
+    Ok(ExitCode::SUCCESS)
+}
 
-    let task_dir = match args.task_dir {
-        Some(dir) => dir,
-        None => match config.task_dir {
-            Some(dir) => dir,
-            None => default_task_dir()?,
-        },
-    };
+fn run(args: Cli) -> Result<ExitCode> {
+    // Completions don't depend on the config, and generating them shouldn't create one
+    if let Some(Command::Completions { shell }) = args.command {
+        print!("{}", completions::generate(Cli::command(), shell));
+        return Ok(ExitCode::SUCCESS);
+    }
 
-    let Ok(task_bin) = which::which("task") else {
-        bail!("Unable to find taskwarrior binary ('task') on the $PATH");
+    // Only needed for whatever isn't given explicitly, so not having them is only an error then
+    let proj_dirs = ProjectDirs::from("", "", THIS_BIN_NAME);
+    let config_file = match (&args.config, &proj_dirs) {
+        (Some(config_file), _) => config_file.clone(),
+        (None, Some(proj_dirs)) => proj_dirs.config_dir().join("config.toml"),
+        (None, None) => bail!(
+            "Unable to find the XDG config dir on this system, pass the config file with --config"
+        ),
     };
-
-    let mut conflicts = Vec::new();
-    let re = Regex::new(PATTERN).unwrap();
-    for entry in fs::read_dir(&task_dir)? {
-        let entry = entry?;
-        let name = entry.file_name().into_string().unwrap();
-        let path = entry.path();
-        let ttype = entry.file_type()?;
-        if ttype.is_file() {
-            if let Some(caps) = re.captures(&name) {
-                let timestamp_str = caps.get(1).unwrap().as_str();
-                let timestamp = DateTime::strptime(SYNCTHING_DATE_FORMAT, timestamp_str)?;
-                let device = caps.get(2).unwrap().as_str().to_owned();
-                conflicts.push((timestamp, device, path));
+    if args.init {
+        match config_file.is_file() {
+            true => {
+                // Still worth knowing whether the one that is there would work
+                Config::load(&config_file)?;
+                eprintln!("The config file already exists, leaving it as it is");
+            }
+            false => {
+                Config::load_or_create(&config_file).wrap_err_with(|| {
+                    format!("Unable to write the config file {}", config_file.display())
+                })?;
+                eprintln!("Wrote the default config, edit it before resolving");
             }
         }
+        println!("{}", config_file.display());
+        return Ok(ExitCode::SUCCESS);
     }
 
-    // Only perform operations if there are conflicts
-    if !conflicts.is_empty() {
-        // Create a dir to back up conflicted task DBs to prevent data loss
-        let timestamp = Zoned::now().with_time_zone(TimeZone::UTC);
-        let timestamp = timestamp.strftime(DATE_FORMAT).to_string();
-        let action_history_dir = state_dir.join(timestamp);
-        fs::create_dir_all(&action_history_dir)?;
-
-        // Also add the main db to list of conflicts, so it is part of our history merging
-        let main_db_path = task_dir.join("taskchampion.sqlite3");
-        let device = String::from("------"); // Fake device ID, shouldn't matter, we don't use the device ID right now
-        let metadata = fs::metadata(&main_db_path)?;
-        let modified = metadata.modified()?;
-        let timestamp = Zoned::try_from(modified)?;
-        let timestamp = DateTime::from(timestamp);
-        conflicts.push((timestamp, device, main_db_path.clone()));
-
-        // Sort by timestamp
-        conflicts.sort_by_key(|x| x.0);
-
-        // Walk over history, figuring out conflicts
-        let mut hist = History::new();
-        for (timestamp, device, path) in &conflicts {
-            debug!("Timestamp: {}", timestamp);
-
-            let tmp = TempDir::new()?;
-            let tmp_dir = tmp.path();
-            let dest = tmp_dir.join("taskchampion.sqlite3");
-            fs::copy(&path, dest)?;
-
-            // Tell taskwarrior to use the tmpdir to find its DB
-            env::set_var("TASKDATA", &tmp_dir);
-
-            // Get all task with an empty query string
-            debug!("DB: {}", path.display());
-            let tasks = tw::query("")?;
-            for task in tasks {
-                hist.insert(task);
+    let mut config = match &args.config {
+        Some(config_file) => Config::load(config_file)?,
+        None => {
+            let read_only =
+                resolve_args(&args).is_some_and(|resolve| resolve.dry_run || resolve.emit_ndjson);
+            if (read_only || args.dump_config) && !config_file.is_file() {
+                // A dry run must not write anything, including the default config, and neither should looking at it
+                Config::default()
+            } else {
+                Config::load_or_create(&config_file)?
             }
         }
+    };
 
-        // Sort out history conflicts
-        let tasks = hist.merge();
-
-        // Save our tasks in a taskchampion database
-        let json_tasks = serde_json::to_string(&tasks)?;
-        let tmp = TempDir::new()?;
-        let tmp_dir = tmp.path();
-        env::set_var("TASKDATA", &tmp_dir);
-        let Ok(mut child) = Command::new(&task_bin)
-            .args(["import"])
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-        else {
-            bail!("Unable to run '{}'", &task_bin.display());
+    let state_dir = match (&args.state_dir, &config.state_dir) {
+        (Some(dir), _) | (None, Some(dir)) => dir.clone(),
+        (None, None) => match proj_dirs.as_ref().and_then(|dirs| dirs.state_dir()) {
+            Some(dir) => dir.to_path_buf(),
+            None => bail!(
+                "Unable to find the XDG state dir on this system, set state_dir in the config or pass --state-dir"
+            ),
+        },
+    };
+    let state_dir = state_dir.as_path();
+
+    let targets = target::targets(args.task_dir.clone(), &config, state_dir)?;
+
+    if args.dump_config {
+        let mut config = config.with_defaults();
+        config.state_dir = Some(state_dir.to_path_buf());
+        if let Some(resolve) = resolve_args(&args) {
+            config.keep = resolve.keep.or(config.keep);
+            config.parallelism = resolve.parallel.or(config.parallelism);
+            if resolve.dry_run {
+                config.dry_run = Some(true);
+            } else if resolve.apply {
+                config.dry_run = Some(false);
+            }
+            if resolve.no_backup {
+                config.backup = Some(false);
+            }
+        }
+        // Show the binary that would actually be run, or what was asked for when it can't be found
+        let task_bin = args.task_bin.clone().or(config.task_bin.take());
+        config.task_bin = taskwarrior::find_task_bin(task_bin.as_deref())
+            .ok()
+            .or(task_bin);
+
+        let effective = EffectiveConfig {
+            config_file,
+            config,
+            targets,
         };
+        print!("{}", toml::to_string_pretty(&effective)?);
+        return Ok(ExitCode::SUCCESS);
+    }
 
-        // Pass tasks as json to stdin
-        let mut stdin = child.stdin.take().expect("Failed to open stdin");
-        std::thread::spawn(move || {
-            stdin
-                .write_all(json_tasks.as_bytes())
-                .expect("Failed to write to stdin");
-        });
-
-        let output = child.wait_with_output().expect("Failed to read stdout");
-        let stdout = &output.stdout;
-        if !stdout.is_empty() {
-            let stdout = String::from_utf8_lossy(stdout);
-            debug!("task stdout: {}", stdout);
+    if args.cleanup_temp {
+        temp::cleanup(&config)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    match args
+        .command
+        .unwrap_or(Command::Resolve(Box::new(args.resolve)))
+    {
+        Command::Resolve(resolve_args) => {
+            let (task_bin, task_version, _run_temp) =
+                run::prepare_resolve(args.task_bin.as_deref(), &mut config, &resolve_args)?;
+            if resolve_args.review_out.is_some() && targets.len() > 1 {
+                bail!("Several task dirs are configured, pick the one to review with --task-dir");
+            }
+            if resolve_args.emit_ndjson && targets.len() > 1 {
+                bail!("Several task dirs are configured, pick the one to emit with --task-dir");
+            }
+            run::resolve_targets(
+                &task_bin,
+                task_version.as_deref(),
+                &targets,
+                &config,
+                &resolve_args,
+            )
         }
-        let stderr = &output.stderr;
-        if !stderr.is_empty() {
-            let stderr = String::from_utf8_lossy(&stderr);
-            warn!("task stderr: {}", stderr);
+        Command::Watch {
+            debounce,
+            mut resolve,
+        } => {
+            if resolve.review_out.is_some()
+                || resolve.explain.is_some()
+                || resolve.hook.is_some()
+                || resolve.print_backup_dir
+                || resolve.emit_ndjson
+            {
+                bail!(
+                    "--review-out, --explain, --hook, --print-backup-dir, and --emit-ndjson can't be used with watch"
+                );
+            }
+            let debounce = Duration::try_from(debounce).wrap_err_with(|| {
+                format!(
+                    "Unable to use {} as the debounce interval, give it in hours, minutes, or seconds",
+                    debounce
+                )
+            })?;
+            // Nobody is there to answer a prompt in the middle of watching
+            resolve.yes = true;
+            let (task_bin, task_version, _run_temp) =
+                run::prepare_resolve(args.task_bin.as_deref(), &mut config, &resolve)?;
+            watch::watch(
+                &task_bin,
+                task_version.as_deref(),
+                &targets,
+                &config,
+                &resolve,
+                debounce,
+            )
         }
-
-        // Backup and remove conflict databases (this includes the main db!)
-        for (_, _, path) in &conflicts {
-            let file_name = path.file_name().unwrap();
-            let dest = action_history_dir.join(&file_name);
-            debug!("Backing up {}", &dest.display());
-            fs::copy(&path, &dest)?;
-            fs::remove_file(path)?
+        Command::List => {
+            for target in &targets {
+                run::list(&target.task_dir, &config)?;
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::MergeFiles { a, b, out } => {
+            let task_bin =
+                taskwarrior::task_bin(args.task_bin.as_deref(), &config, config.uses_task_bin())?;
+            if config.uses_task_bin() {
+                taskwarrior::check_version(&task_bin, false)?;
+            }
+            let _run_temp = temp::run_dir(&mut config)?;
+            merge_files::merge(&task_bin, &config, &a, &b, &out)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Restore { timestamp } => {
+            let matching: Vec<&Target> = targets
+                .iter()
+                .filter(|target| target.state_dir.join(&timestamp).is_dir())
+                .collect();
+            match matching.as_slice() {
+                [] => bail!("No backup named '{}' in {}", timestamp, state_dir.display()),
+                [target] => {
+                    restore::restore(&target.task_dir, &target.state_dir, &timestamp, &config)?
+                }
+                _ => bail!(
+                    "A backup named '{}' exists for several task dirs, pick one with --task-dir",
+                    timestamp
+                ),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Completions { .. } => {
+            unreachable!("completions are printed before loading the config")
+        }
+        Command::Doctor => {
+            let task_bin = args.task_bin.as_deref().or(config.task_bin.as_deref());
+            let ok = doctor::doctor(task_bin, &config_file, &config, &targets)?;
+            Ok(if ok {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            })
+        }
+        Command::Purge { older_than, yes } => {
+            backup::purge(&targets, config.backup_date_format(), older_than, yes)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Undo { force } => {
+            let [target] = targets.as_slice() else {
+                bail!("Several task dirs are configured, pick the one to undo with --task-dir");
+            };
+            restore::undo(&target.task_dir, &target.state_dir, &config, force)?;
+            Ok(ExitCode::SUCCESS)
         }
-
-        // Replace the main db with the updated tasks
-        let updated_db = tmp_dir.join("taskchampion.sqlite3");
-        fs::copy(&updated_db, &main_db_path)?;
     }
+}
 
-    // Finally, do a little cleanup in the state dir if we have too many entries
-    let num_to_keep = match config.keep {
-        Some(n) => n,
-        None => DEFAULT_KEEP_NUM,
-    };
-    let mut entries: Vec<(DateTime, PathBuf)> = fs::read_dir(&state_dir)?
-        .map(|x| {
-            let path = x.unwrap().path();
-            let file_name = path.file_name().unwrap().to_str().unwrap();
-            let timestamp = DateTime::strptime(DATE_FORMAT, file_name).unwrap();
-            (timestamp, path)
-        })
-        .collect();
-    let num_entries = entries.len();
-    if num_entries > num_to_keep {
-        let diff = num_entries - num_to_keep;
-
-        // Sort by timestamp
-        entries.sort_by_key(|x| x.0);
-
-        // Remove the first n entries to get down to num_to_keep
-        let mut iter = entries.iter();
-        for _ in 0..diff {
-            let (_, path) = iter.next().unwrap();
-            fs::remove_dir_all(&path)?;
-        }
+/// The options for resolving, whether given to the `resolve` subcommand or to no subcommand at all
+fn resolve_args(args: &Cli) -> Option<&ResolveArgs> {
+    match &args.command {
+        Some(Command::Resolve(resolve)) => Some(resolve),
+        Some(_) => None,
+        None => Some(&args.resolve),
     }
+}
 
-    Ok(())
+/// What --dump-config prints
+#[derive(Debug, Serialize)]
+struct EffectiveConfig {
+    config_file: PathBuf,
+    #[serde(flatten)]
+    config: Config,
+    targets: Vec<Target>,
 }
@@ -0,0 +1,85 @@
+use crate::config::Config;
+use crate::{ApplyOptions, Conflict, ResolveOptions, Source};
+use color_eyre::{eyre::bail, eyre::WrapErr, Result};
+use jiff::{civil::DateTime, Zoned};
+use std::fs;
+use std::path::Path;
+use task_hookrs::task::Task;
+use tracing::warn;
+
+/// Merge the databases `a` and `b` into a new database at `out`. Each is treated like a conflict file from a device
+/// named after its file name, which breaks ties between snapshots modified at the same second
+pub fn merge(task_bin: &Path, config: &Config, a: &Path, b: &Path, out: &Path) -> Result<()> {
+    if out.exists() {
+        bail!(
+            "{} already exists, pick a new path for --out",
+            out.display()
+        );
+    }
+    let mut conflicts = Vec::new();
+    for path in [a, b] {
+        if !crate::is_sqlite_db(path)
+            .wrap_err_with(|| format!("Unable to read {}", path.display()))?
+        {
+            bail!("{} is not a valid SQLite database", path.display());
+        }
+        if crate::wal_path(path).is_file() {
+            warn!(
+                "{} has a write-ahead log, changes not yet checkpointed into it are not merged",
+                path.display()
+            );
+        }
+        let modified = fs::metadata(path)?.modified()?;
+        let device = path.file_name().map_or_else(
+            || path.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        conflicts.push(Conflict {
+            path: path.to_path_buf(),
+            source: Source::Conflict { device },
+            timestamp: DateTime::from(Zoned::try_from(modified)?),
+        });
+    }
+
+    let opts = ResolveOptions {
+        task_bin,
+        read_backend: config.read_backend.unwrap_or_default(),
+        merge_strategy: config.merge_strategy.unwrap_or_default(),
+        sticky_completion: config.sticky_completion.unwrap_or(false),
+        winner_priority: config.winner_priority.as_deref().unwrap_or_default(),
+        task_filter: None,
+        temp_dir: config.temp_dir.as_deref(),
+        parallelism: config.parallelism,
+        progress: false,
+        verify: true,
+    };
+    let tasks: Vec<Task> = crate::resolve(&conflicts, &opts)?
+        .merged
+        .into_iter()
+        .map(|m| m.task)
+        .collect();
+
+    // Written next to `out` first, so it only ever appears complete
+    let parent = match out.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let tmp = tempfile::TempDir::new_in(parent).wrap_err_with(|| {
+        format!(
+            "Unable to create a temporary directory in {}",
+            parent.display()
+        )
+    })?;
+    let apply_opts = ApplyOptions {
+        task_bin,
+        write_backend: config.write_backend.unwrap_or_default(),
+        retry: config.retry(),
+        temp_dir: config.temp_dir.as_deref(),
+        // Only the merged database is written, the inputs are never touched
+        delete_conflicts: false,
+    };
+    let db = crate::write_database(&apply_opts, &tasks, tmp.path())?;
+    fs::rename(&db, out).wrap_err_with(|| format!("Unable to write {}", out.display()))?;
+    println!("Merged {} tasks into {}", tasks.len(), out.display());
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use crate::fsutil;
+use color_eyre::{eyre::WrapErr, Result};
+use jiff::Timestamp;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use tracing::debug;
+
+const PREFIX: &str = "syncthing_task_resolve";
+
+/// One metric, and how to get its value for a run
+struct Metric {
+    name: &'static str,
+    kind: Kind,
+    help: &'static str,
+    value: fn(&Run, Timestamp) -> f64,
+}
+
+const METRICS: &[Metric] = &[
+    Metric {
+        name: "conflicts_total",
+        kind: Kind::Counter,
+        help: "Conflict files resolved",
+        value: |run, _| run.conflicts as f64,
+    },
+    Metric {
+        name: "tasks_merged_total",
+        kind: Kind::Counter,
+        help: "Tasks merged into the main database",
+        value: |run, _| run.merged_tasks as f64,
+    },
+    Metric {
+        name: "last_run_timestamp_seconds",
+        kind: Kind::Gauge,
+        help: "Unix time of the last run",
+        value: |_, now| now.as_second() as f64,
+    },
+    Metric {
+        name: "backup_dirs",
+        kind: Kind::Gauge,
+        help: "Backup directories in the state dir",
+        value: |run, _| run.backup_dirs as f64,
+    },
+];
+
+/// What one task dir contributed to a run
+#[derive(Debug)]
+pub struct Run<'a> {
+    pub task_dir: &'a Path,
+
+    /// Conflict files resolved, zero unless the merge was applied
+    pub conflicts: usize,
+
+    /// Tasks written to the main database, zero unless the merge was applied
+    pub merged_tasks: usize,
+
+    /// Backup dirs in the state dir after this run
+    pub backup_dirs: usize,
+}
+
+/// Write the metrics of `runs` to `path` in the Prometheus text format, for node_exporter's textfile collector.
+/// The counters continue from the values already in `path`, so they count across runs. The file is replaced
+/// atomically, so the collector never reads a partial one
+pub fn write(path: &Path, runs: &[Run], now: Timestamp) -> Result<()> {
+    let previous = read_previous(path);
+    let mut out = String::new();
+    for metric in METRICS {
+        let name = format!("{}_{}", PREFIX, metric.name);
+        let kind = match metric.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        writeln!(out, "# HELP {} {}", name, metric.help)?;
+        writeln!(out, "# TYPE {} {}", name, kind)?;
+        for run in runs {
+            let series = format!("{}{}", name, labels(run.task_dir));
+            let value = (metric.value)(run, now);
+            let value = match metric.kind {
+                Kind::Counter => previous.get(&series).copied().unwrap_or(0.0) + value,
+                Kind::Gauge => value,
+            };
+            writeln!(out, "{} {}", series, value)?;
+        }
+    }
+
+    debug!("Writing metrics to {}", path.display());
+    let tmp = fsutil::tmp_sibling(path);
+    fs::write(&tmp, out).wrap_err_with(|| format!("Unable to write {}", tmp.display()))?;
+    fsutil::replace_atomically(&tmp, path)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    /// Only ever goes up, across runs
+    Counter,
+    /// The value as of this run
+    Gauge,
+}
+
+/// The label set identifying a task dir, e.g. `{task_dir="/home/me/.local/share/task"}`
+fn labels(task_dir: &Path) -> String {
+    let dir = task_dir
+        .display()
+        .to_string()
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n");
+    format!("{{task_dir=\"{}\"}}", dir)
+}
+
+/// Values in an existing metrics file, by metric name and labels. A missing or unreadable file starts from zero
+fn read_previous(path: &Path) -> HashMap<String, f64> {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+    contents
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (key, value) = line.rsplit_once(' ')?;
+            Some((key.to_owned(), value.parse().ok()?))
+        })
+        .collect()
+}
@@ -0,0 +1,214 @@
+//! Reading and writing taskchampion databases in-process with the `taskchampion` crate, instead of through
+//! `task export` and `task import`.
+//!
+//! taskchampion stores each task as a flat map of string properties: dates as unix timestamps, and tags, annotations,
+//! and dependencies as one `tag_<name>`, `annotation_<timestamp>`, or `dep_<uuid>` property each. These are turned
+//! into the same JSON that `task export` prints and back, so the rest of the merge can't tell the two apart.
+
+use color_eyre::{eyre::bail, eyre::eyre, eyre::WrapErr, Result};
+use jiff::fmt::strtime;
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use serde_json::{Map, Value};
+use std::path::Path;
+use task_hookrs::task::Task;
+use taskchampion::{Operations, Replica, StorageConfig, TaskData};
+use uuid::Uuid;
+
+/// Attributes `task export` computes, which are not stored in the database
+pub(crate) const COMPUTED_FIELDS: &[&str] = &["id", "urgency"];
+
+/// Attributes taskchampion stores as a unix timestamp, which taskwarrior exports as e.g. `20240102T030405Z`
+pub(crate) const DATE_FIELDS: &[&str] = &[
+    "entry",
+    "start",
+    "end",
+    "due",
+    "until",
+    "wait",
+    "scheduled",
+    "modified",
+];
+
+/// Read all tasks from the database in `data_dir`
+pub fn read(data_dir: &Path) -> Result<Vec<Task>> {
+    let storage = StorageConfig::OnDisk {
+        taskdb_dir: data_dir.to_owned(),
+        create_if_missing: false,
+    }
+    .into_storage()
+    .wrap_err_with(|| format!("Unable to open the database in {}", data_dir.display()))?;
+    let mut replica = Replica::new(storage);
+
+    let mut tasks = replica
+        .all_task_data()
+        .wrap_err_with(|| format!("Unable to read tasks from {}", data_dir.display()))?
+        .into_values()
+        .map(|data| to_task(&data))
+        .collect::<Result<Vec<Task>>>()?;
+    // Roughly the order `task export` uses, rather than whatever order the hash map was in
+    tasks.sort_by_key(|task| (**task.entry(), *task.uuid()));
+    Ok(tasks)
+}
+
+/// Write `tasks` into a new database in `data_dir`
+pub fn write(data_dir: &Path, tasks: &[Task]) -> Result<()> {
+    let storage = StorageConfig::OnDisk {
+        taskdb_dir: data_dir.to_owned(),
+        create_if_missing: true,
+    }
+    .into_storage()
+    .wrap_err_with(|| format!("Unable to create a database in {}", data_dir.display()))?;
+    let mut replica = Replica::new(storage);
+
+    let mut ops = Operations::new();
+    for task in tasks {
+        let mut data = TaskData::create(*task.uuid(), &mut ops);
+        for (key, value) in to_properties(task)? {
+            data.update(key, Some(value), &mut ops);
+        }
+    }
+    replica
+        .commit_operations(ops)
+        .wrap_err_with(|| format!("Unable to write tasks to {}", data_dir.display()))?;
+    Ok(())
+}
+
+/// The task as `task export` would print it
+fn to_task(data: &TaskData) -> Result<Task> {
+    let uuid = data.get_uuid();
+    let mut fields = Map::new();
+    let mut tags = Vec::new();
+    let mut annotations = Vec::new();
+    let mut depends = Vec::new();
+
+    // The properties come in no particular order. Sorted, tags and dependencies always come out the same, and
+    // annotations in the order they were made, the way taskwarrior exports them
+    let mut properties: Vec<(&String, &String)> = data.iter().collect();
+    properties.sort();
+    for (key, value) in properties {
+        if let Some(tag) = key.strip_prefix("tag_") {
+            tags.push(Value::from(tag));
+        } else if let Some(entry) = key.strip_prefix("annotation_") {
+            let mut annotation = Map::new();
+            annotation.insert("entry".to_owned(), export_date(uuid, key, entry)?);
+            annotation.insert("description".to_owned(), Value::from(value.as_str()));
+            annotations.push(Value::Object(annotation));
+        } else if let Some(dep) = key.strip_prefix("dep_") {
+            depends.push(Value::from(dep));
+        } else if DATE_FIELDS.contains(&key.as_str()) {
+            fields.insert(key.clone(), export_date(uuid, key, value)?);
+        } else if key == "imask" {
+            let imask: f64 = value
+                .parse()
+                .wrap_err_with(|| format!("Task {} has an invalid imask '{}'", uuid, value))?;
+            fields.insert(key.clone(), Value::from(imask));
+        } else {
+            fields.insert(key.clone(), Value::from(value.as_str()));
+        }
+    }
+
+    fields.insert("uuid".to_owned(), Value::from(uuid.to_string()));
+    // taskwarrior exports these even when the task never had them set
+    fields
+        .entry("description")
+        .or_insert_with(|| Value::from(""));
+    fields
+        .entry("status")
+        .or_insert_with(|| Value::from("pending"));
+    for (key, list) in [
+        ("tags", tags),
+        ("annotations", annotations),
+        ("depends", depends),
+    ] {
+        if !list.is_empty() {
+            fields.insert(key.to_owned(), Value::Array(list));
+        }
+    }
+
+    serde_json::from_value(Value::Object(fields))
+        .wrap_err_with(|| format!("Unable to read task {}", uuid))
+}
+
+/// The properties taskchampion stores for the task, the reverse of `to_task`
+fn to_properties(task: &Task) -> Result<Vec<(String, String)>> {
+    let uuid = *task.uuid();
+    let Value::Object(fields) = serde_json::to_value(task)? else {
+        bail!("Task {} did not serialize to a JSON object", uuid);
+    };
+
+    let mut properties = Vec::new();
+    for (key, value) in fields {
+        if key == "uuid" || COMPUTED_FIELDS.contains(&key.as_str()) {
+            continue;
+        }
+        match (key.as_str(), value) {
+            ("tags", Value::Array(tags)) => {
+                for tag in tags {
+                    properties.push((format!("tag_{}", string(uuid, &key, &tag)?), String::new()));
+                }
+            }
+            ("depends", Value::Array(depends)) => {
+                for dep in depends {
+                    properties.push((format!("dep_{}", string(uuid, &key, &dep)?), String::new()));
+                }
+            }
+            ("annotations", Value::Array(annotations)) => {
+                for annotation in annotations {
+                    let entry = string(uuid, &key, &annotation["entry"])?;
+                    let description = string(uuid, &key, &annotation["description"])?;
+                    properties.push((
+                        format!("annotation_{}", import_date(uuid, &key, &entry)?),
+                        description,
+                    ));
+                }
+            }
+            (_, value) if DATE_FIELDS.contains(&key.as_str()) => {
+                let date = import_date(uuid, &key, &string(uuid, &key, &value)?)?;
+                properties.push((key, date.to_string()));
+            }
+            (_, Value::Number(number)) => {
+                // imask is read as a float, but taskwarrior stores the whole number it always is
+                let value = match number.as_f64() {
+                    Some(f) if f.fract() == 0.0 => (f as i64).to_string(),
+                    _ => number.to_string(),
+                };
+                properties.push((key, value));
+            }
+            (_, value) => {
+                let value = string(uuid, &key, &value)?;
+                properties.push((key, value));
+            }
+        }
+    }
+    Ok(properties)
+}
+
+/// A JSON string attribute of a task
+fn string(uuid: Uuid, key: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        _ => bail!("Task {} has an unexpected value {} in {}", uuid, value, key),
+    }
+}
+
+/// A date as taskwarrior exports it, as a unix timestamp
+fn import_date(uuid: Uuid, key: &str, value: &str) -> Result<i64> {
+    let timestamp = strtime::parse("%Y%m%dT%H%M%SZ", value)
+        .and_then(|time| time.to_datetime())
+        .and_then(|datetime| datetime.to_zoned(TimeZone::UTC))
+        .wrap_err_with(|| format!("Task {} has an invalid date '{}' in {}", uuid, value, key))?;
+    Ok(timestamp.timestamp().as_second())
+}
+
+/// A unix timestamp as taskwarrior exports dates
+fn export_date(uuid: Uuid, key: &str, value: &str) -> Result<Value> {
+    let timestamp = value
+        .parse()
+        .ok()
+        .and_then(|seconds| Timestamp::from_second(seconds).ok())
+        .ok_or_else(|| eyre!("Task {} has an invalid date '{}' in {}", uuid, value, key))?;
+    Ok(Value::from(
+        timestamp.strftime("%Y%m%dT%H%M%SZ").to_string(),
+    ))
+}
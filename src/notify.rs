@@ -0,0 +1,58 @@
+//! Desktop notifications, for `notify = true`.
+//!
+//! They are shown with the platform's own tool, `notify-send` (libnotify) on Linux and the BSDs, and `osascript` on
+//! macOS.
+
+// TODO: switch to notify-rust, which talks to the notification daemon directly, once it can be added as a dependency
+
+use std::env;
+use std::process::{Command, Stdio};
+use tracing::{debug, warn};
+
+/// Show a desktop notification.
+/// This is best effort: on headless systems, or when no notification daemon is running, nothing happens.
+pub fn send(summary: &str, body: &str) {
+    let Some(mut command) = command(summary, body) else {
+        return;
+    };
+    let status = command.stdout(Stdio::null()).stderr(Stdio::null()).status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => debug!("{:?} exited with {}", command.get_program(), status),
+        Err(e) => debug!("Unable to run {:?}: {}", command.get_program(), e),
+    }
+}
+
+/// The command showing the notification. None when there is nowhere to show it, or nothing to show it with
+fn command(summary: &str, body: &str) -> Option<Command> {
+    if cfg!(target_os = "macos") {
+        // The texts are passed as arguments, so they need no quoting for AppleScript
+        let mut command = Command::new("osascript");
+        command.args([
+            "-e",
+            "on run argv",
+            "-e",
+            "display notification (item 2 of argv) with title (item 1 of argv)",
+            "-e",
+            "end run",
+            summary,
+            body,
+        ]);
+        return Some(command);
+    }
+
+    if env::var_os("DISPLAY").is_none() && env::var_os("WAYLAND_DISPLAY").is_none() {
+        debug!("No graphical session, skipping desktop notification");
+        return None;
+    }
+    let Ok(notify_send) = which::which("notify-send") else {
+        warn!(
+            "'notify-send' not found on the $PATH, install libnotify (e.g. libnotify-bin) for the desktop \
+             notifications notify = true asks for"
+        );
+        return None;
+    };
+    let mut command = Command::new(notify_send);
+    command.args(["--app-name", crate::THIS_BIN_NAME, summary, body]);
+    Some(command)
+}
@@ -0,0 +1,41 @@
+//! The taskwarrior hook protocol, as far as an `on-launch` hook needs it. Taskwarrior runs the hook with arguments
+//! like `api:2 args:'task list' command:list rc:/home/me/.taskrc data:/home/me/.local/share/task version:3.1.0`,
+//! shows what it prints to stdout as feedback, and only goes on with the command when it exits with 0.
+
+use color_eyre::{eyre::bail, Result};
+use std::path::PathBuf;
+use tracing::warn;
+
+/// The only hook API version with arguments. Version 1 passes nothing at all
+const API_VERSION: &str = "2";
+
+/// What taskwarrior told the hook
+#[derive(Debug, Default)]
+pub struct Launch {
+    /// Taskwarrior's data location, after `rc.data.location` overrides
+    pub data: Option<PathBuf>,
+}
+
+impl Launch {
+    /// Parse the `key:value` arguments taskwarrior passes to hooks. Unknown keys are ignored, so newer taskwarrior
+    /// versions can add some
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut launch = Self::default();
+        for arg in args {
+            let Some((key, value)) = arg.split_once(':') else {
+                bail!("Unexpected hook argument '{}', expected 'key:value'", arg);
+            };
+            match key {
+                "api" if value != API_VERSION => {
+                    warn!(
+                        "Taskwarrior uses hook API version {}, only {} is known",
+                        value, API_VERSION
+                    );
+                }
+                "data" if !value.is_empty() => launch.data = Some(PathBuf::from(value)),
+                _ => {}
+            }
+        }
+        Ok(launch)
+    }
+}
@@ -0,0 +1,105 @@
+//! Progress bars on stderr while reading databases and merging tasks.
+
+// TODO: draw them with indicatif once it can be added as a dependency
+
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Width of the bar itself, in characters
+const WIDTH: usize = 30;
+
+/// A progress bar on stderr, e.g. `Reading databases [=========>          ] 12/40`. It can be advanced from several
+/// threads at once, and only redraws when the bar visibly moves. Nothing is drawn when stderr isn't a terminal, where
+/// the carriage returns would only clutter a log
+#[derive(Debug)]
+pub struct Progress {
+    label: &'static str,
+    total: usize,
+    done: AtomicUsize,
+    hidden: bool,
+}
+
+impl Progress {
+    pub fn new(label: &'static str, total: usize) -> Self {
+        let progress = Self {
+            label,
+            total,
+            done: AtomicUsize::new(0),
+            hidden: !io::stderr().is_terminal(),
+        };
+        progress.draw(0);
+        progress
+    }
+
+    /// Count one more item as done
+    pub fn inc(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.filled(done) != self.filled(done - 1) || done == self.total {
+            self.draw(done);
+        }
+    }
+
+    fn filled(&self, done: usize) -> usize {
+        (done * WIDTH).checked_div(self.total).unwrap_or(WIDTH)
+    }
+
+    fn draw(&self, done: usize) {
+        if self.hidden {
+            return;
+        }
+        let filled = self.filled(done).min(WIDTH);
+        let bar = match filled {
+            WIDTH => "=".repeat(WIDTH),
+            _ => format!("{}>{}", "=".repeat(filled), " ".repeat(WIDTH - filled - 1)),
+        };
+        // Progress is only ever a nicety, so failing to draw it is no reason to stop
+        let _ = write!(
+            io::stderr(),
+            "\r{} [{}] {}/{}",
+            self.label,
+            bar,
+            done,
+            self.total
+        );
+    }
+}
+
+impl Drop for Progress {
+    /// Clear the bar, so whatever is printed next starts on a clean line
+    fn drop(&mut self) {
+        if !self.hidden {
+            let _ = write!(io::stderr(), "\r\x1b[K");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filled_follows_the_items_done() {
+        let progress = Progress {
+            label: "test",
+            total: 60,
+            done: AtomicUsize::new(0),
+            hidden: true,
+        };
+        assert_eq!(progress.filled(0), 0);
+        assert_eq!(progress.filled(1), 0);
+        assert_eq!(progress.filled(2), 1);
+        assert_eq!(progress.filled(30), WIDTH / 2);
+        assert_eq!(progress.filled(60), WIDTH);
+    }
+
+    #[test]
+    fn nothing_to_do_is_a_full_bar() {
+        let progress = Progress {
+            label: "test",
+            total: 0,
+            done: AtomicUsize::new(0),
+            hidden: true,
+        };
+        assert_eq!(progress.filled(0), WIDTH);
+    }
+}
@@ -0,0 +1,11 @@
+use color_eyre::Result;
+use std::io::{self, Write};
+
+/// Ask on stderr whether to go ahead, reading the answer from stdin. Anything but "y" or "yes" is a no
+pub fn confirm(question: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
@@ -0,0 +1,153 @@
+use crate::config::MergeStrategy;
+use crate::history::Merged;
+use crate::{Conflict, Source};
+use color_eyre::{eyre::bail, eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+pub const REPORT_FILE_NAME: &str = "report.toml";
+
+/// Record of a resolve run, saved next to the backed up databases so each backup dir describes itself
+#[derive(Debug, Serialize)]
+pub struct Report {
+    main_db: PathBuf,
+    merge_strategy: MergeStrategy,
+
+    /// What `task --version` reported, when taskwarrior was used for the merge
+    #[serde(skip_serializing_if = "Option::is_none")]
+    task_version: Option<String>,
+    conflicts: Vec<Conflict>,
+
+    /// Tasks found in more than one database
+    tasks: Vec<TaskReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct TaskReport {
+    uuid: Uuid,
+    snapshots: usize,
+
+    /// Whether the snapshots disagreed, rather than just being copies of each other
+    conflicted: bool,
+    winner_device: Source,
+    winner_path: PathBuf,
+}
+
+impl Report {
+    pub fn new(
+        main_db: &Path,
+        merge_strategy: MergeStrategy,
+        task_version: Option<&str>,
+        conflicts: &[Conflict],
+        merged: &[Merged],
+    ) -> Self {
+        let mut tasks: Vec<TaskReport> = merged
+            .iter()
+            .filter(|m| m.snapshots > 1)
+            .map(|m| TaskReport {
+                uuid: *m.task.uuid(),
+                snapshots: m.snapshots,
+                conflicted: m.conflicted,
+                winner_device: m.winner.source.clone(),
+                winner_path: m.winner.path.clone(),
+            })
+            .collect();
+        tasks.sort_by_key(|task| task.uuid);
+
+        Self {
+            main_db: main_db.to_owned(),
+            merge_strategy,
+            task_version: task_version.map(str::to_owned),
+            conflicts: conflicts.to_vec(),
+            tasks,
+        }
+    }
+
+    /// Write the report into `dir`, along with the `checksums` of the files backed up there and the checksum of the
+    /// merged database that replaces the main one
+    pub fn write(
+        &self,
+        dir: &Path,
+        checksums: &BTreeMap<String, String>,
+        merged_checksum: &str,
+    ) -> Result<()> {
+        let path = dir.join(REPORT_FILE_NAME);
+        let mut table = toml::Table::try_from(self)?;
+        table.insert(
+            "merged_checksum".to_owned(),
+            toml::Value::from(merged_checksum),
+        );
+        table.insert("checksums".to_owned(), toml::Value::try_from(checksums)?);
+        let contents = toml::to_string_pretty(&table)?;
+        fs::write(&path, contents)
+            .wrap_err_with(|| format!("Unable to write report {}", path.display()))
+    }
+}
+
+/// The checksums recorded in a backup's report. Backups without a report, or from before checksums were recorded, have
+/// none
+#[derive(Debug, Default, Deserialize)]
+pub struct Checksums {
+    /// SHA-256 of each file backed up, by file name
+    #[serde(default)]
+    pub checksums: BTreeMap<String, String>,
+
+    /// SHA-256 of the database the main one was replaced with: the merged one, or the one restored from a backup
+    pub merged_checksum: Option<String>,
+}
+
+/// Add `checksums` of more files backed up in `dir` to its report, creating a report of just the checksums when there
+/// is none yet (e.g. for the files a restore replaced). `merged_checksum` is recorded as well when given
+pub fn add_checksums(
+    dir: &Path,
+    checksums: &BTreeMap<String, String>,
+    merged_checksum: Option<&str>,
+) -> Result<()> {
+    let path = dir.join(REPORT_FILE_NAME);
+    let mut table: toml::Table = match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents)
+            .wrap_err_with(|| format!("Unable to parse report {}", path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => toml::Table::new(),
+        Err(e) => {
+            return Err(e).wrap_err_with(|| format!("Unable to read report {}", path.display()))
+        }
+    };
+    let recorded = table
+        .entry("checksums")
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    let Some(recorded) = recorded.as_table_mut() else {
+        bail!(
+            "Report {} has checksums that are not a table",
+            path.display()
+        );
+    };
+    for (name, checksum) in checksums {
+        recorded.insert(name.clone(), toml::Value::from(checksum.as_str()));
+    }
+    if let Some(merged_checksum) = merged_checksum {
+        table.insert(
+            "merged_checksum".to_owned(),
+            toml::Value::from(merged_checksum),
+        );
+    }
+    let contents = toml::to_string_pretty(&table)?;
+    fs::write(&path, contents)
+        .wrap_err_with(|| format!("Unable to write report {}", path.display()))
+}
+
+/// The checksums recorded in the report in the backup dir `dir`
+pub fn read_checksums(dir: &Path) -> Result<Checksums> {
+    let path = dir.join(REPORT_FILE_NAME);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Checksums::default()),
+        Err(e) => {
+            return Err(e).wrap_err_with(|| format!("Unable to read report {}", path.display()))
+        }
+    };
+    toml::from_str(&contents).wrap_err_with(|| format!("Unable to parse report {}", path.display()))
+}
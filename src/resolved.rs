@@ -0,0 +1,148 @@
+//! The conflict files that were already merged, so a copy that syncthing brings back after it was resolved isn't
+//! merged all over again.
+
+use crate::fsutil;
+use crate::sha256;
+use color_eyre::{eyre::WrapErr, Result};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+const FILE_NAME: &str = "resolved.toml";
+
+/// Only the most recently resolved files are remembered, so the file doesn't grow forever
+const MAX_ENTRIES: usize = 1000;
+
+/// Conflict files merged by earlier runs, kept in `resolved.toml` in the state dir
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Resolved {
+    #[serde(default)]
+    files: Vec<Entry>,
+}
+
+/// A conflict file as it was when it was merged
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    path: PathBuf,
+    modified: Timestamp,
+    hash: String,
+}
+
+impl Entry {
+    /// The file at `path` as it is now
+    pub fn of(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            modified: modified(path)?,
+            hash: hash(path)?,
+        })
+    }
+}
+
+impl Resolved {
+    /// The files resolved for the task dir whose state dir is `state_dir`. A missing file means none were, and a
+    /// broken one is only warned about, since the worst that can happen without it is merging a file again
+    pub fn load(state_dir: &Path) -> Self {
+        let path = state_dir.join(FILE_NAME);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                warn!("Unable to read {}: {}", path.display(), e);
+                return Self::default();
+            }
+        };
+        toml::from_str(&contents).unwrap_or_else(|e| {
+            warn!("Ignoring {}, it is not valid: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    /// Whether the file at `path` is unchanged since it was resolved. It is only hashed when its path and mtime match
+    pub fn contains(&self, path: &Path) -> io::Result<bool> {
+        let modified = modified(path)?;
+        let mut matching = self
+            .files
+            .iter()
+            .filter(|entry| entry.path == path && entry.modified == modified)
+            .peekable();
+        if matching.peek().is_none() {
+            return Ok(false);
+        }
+        let hash = hash(path)?;
+        Ok(matching.any(|entry| entry.hash == hash))
+    }
+
+    /// Remember `entries` as resolved, replacing what was remembered about the same paths
+    pub fn add(&mut self, entries: impl IntoIterator<Item = Entry>) {
+        for entry in entries {
+            self.files.retain(|old| old.path != entry.path);
+            self.files.push(entry);
+        }
+        let excess = self.files.len().saturating_sub(MAX_ENTRIES);
+        self.files.drain(..excess);
+    }
+
+    pub fn save(&self, state_dir: &Path) -> Result<()> {
+        let path = state_dir.join(FILE_NAME);
+        debug!("Writing {}", path.display());
+        fs::create_dir_all(state_dir)?;
+        let tmp = fsutil::tmp_sibling(&path);
+        fs::write(&tmp, toml::to_string_pretty(self)?)
+            .wrap_err_with(|| format!("Unable to write {}", tmp.display()))?;
+        fsutil::replace_atomically(&tmp, &path)
+    }
+}
+
+fn modified(path: &Path) -> io::Result<Timestamp> {
+    let modified = fs::metadata(path)?.modified()?;
+    Timestamp::try_from(modified).map_err(io::Error::other)
+}
+
+/// The same SHA-256 the backups are checked with. Hashes recorded before it was used just never match, so those files
+/// are merged once more
+fn hash(path: &Path) -> io::Result<String> {
+    sha256::digest_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::SystemTime;
+
+    #[test]
+    fn a_file_changed_in_place_is_no_longer_resolved() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("conflict");
+        fs::write(&path, b"merged").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let entry = Entry::of(&path).unwrap();
+        assert_eq!(entry.hash, sha256::digest_file(&path).unwrap());
+        let mut resolved = Resolved::default();
+        resolved.add([entry]);
+        resolved.save(dir.path()).unwrap();
+        let resolved = Resolved::load(dir.path());
+        assert!(resolved.contains(&path).unwrap());
+
+        // Same size and mtime, different contents
+        fs::write(&path, b"MERGED").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        assert!(!resolved.contains(&path).unwrap());
+    }
+}
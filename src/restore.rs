@@ -0,0 +1,344 @@
+//! Putting backups from the state dir back into the task dir.
+
+use crate::backup::{self, Backup};
+use crate::config::Config;
+use crate::fsutil;
+use crate::lock::Lock;
+use crate::report;
+use crate::sha256;
+use crate::taskwarrior;
+use crate::MAIN_DB_NAME;
+use color_eyre::{eyre::bail, eyre::WrapErr, Result};
+use jiff::Zoned;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// Copy every file from a backup dir in the state dir back into the task dir, after backing up the files it replaces
+pub fn restore(task_dir: &Path, state_dir: &Path, timestamp: &str, config: &Config) -> Result<()> {
+    let backup_dir = state_dir.join(timestamp);
+    if !backup_dir.is_dir() {
+        bail!("No backup named '{}' in {}", timestamp, state_dir.display());
+    }
+    let _lock = Lock::acquire(state_dir)?;
+
+    // Every file is checked before the first is copied, so a corrupted backup is never partly restored
+    let checksums = report::read_checksums(&backup_dir)?;
+    backup::verify(&backup_dir, &checksums.checksums, |name| {
+        name != report::REPORT_FILE_NAME
+    })
+    .wrap_err_with(|| format!("Refusing to restore from {}", backup_dir.display()))?;
+
+    // The restored main db must not be read together with the write-ahead log of the one it replaces
+    checkpoint(task_dir)?;
+
+    // Everything is copied out next to where it goes first, so a failed copy (e.g. a full disk) leaves the task dir
+    // as it was
+    let mut restored: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let mut restored_main_checksum = None;
+    let copied = backup::read_files(&backup_dir, |name, _, contents| {
+        if name == report::REPORT_FILE_NAME {
+            return Ok(());
+        }
+        let dest = task_dir.join(name);
+        let tmp = fsutil::tmp_sibling(&dest);
+        restored.push((tmp.clone(), dest));
+        io::copy(contents, &mut fs::File::create(&tmp)?)
+            .wrap_err_with(|| format!("Unable to write {}", tmp.display()))?;
+        if name == MAIN_DB_NAME {
+            restored_main_checksum = Some(sha256::digest_file(&tmp)?);
+        }
+        Ok(())
+    });
+    let remove_tmps = |restored: &[(PathBuf, PathBuf)]| {
+        for (tmp, _) in restored {
+            let _ = fs::remove_file(tmp);
+        }
+    };
+    if let Err(e) = copied {
+        remove_tmps(&restored);
+        return Err(e).wrap_err_with(|| format!("Unable to restore from {}", backup_dir.display()));
+    }
+
+    // The files being overwritten get a backup of their own, which `undo` can bring back
+    let replaced: Vec<&Path> = restored
+        .iter()
+        .map(|(_, dest)| dest.as_path())
+        .filter(|dest| dest.is_file())
+        .collect();
+    if let Err(e) = back_up_replaced(
+        state_dir,
+        config,
+        &replaced,
+        restored_main_checksum.as_deref(),
+    ) {
+        remove_tmps(&restored);
+        return Err(e).wrap_err(
+            "Unable to back up the files the restore would replace, nothing was restored",
+        );
+    }
+
+    for (tmp, dest) in &restored {
+        info!("Restoring {} from {}", dest.display(), backup_dir.display());
+        fsutil::replace_atomically(tmp, dest)?;
+    }
+    Ok(())
+}
+
+/// Copy the main database from the newest backup over the current one, after backing up the current one
+pub fn undo(task_dir: &Path, state_dir: &Path, config: &Config, force: bool) -> Result<()> {
+    let _lock = Lock::acquire(state_dir)?;
+
+    let Some((_, backup_dir)) = backup::list(state_dir, config.backup_date_format())?.pop() else {
+        bail!("No backups in {}", state_dir.display());
+    };
+    // The main database may be archived, so copy it out next to where it goes before deciding whether to use it
+    let main_db_path = task_dir.join(MAIN_DB_NAME);
+    let tmp = fsutil::tmp_sibling(&main_db_path);
+    let undone = undo_with(
+        task_dir,
+        &main_db_path,
+        &tmp,
+        &backup_dir,
+        state_dir,
+        config,
+        force,
+    );
+    if undone.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    undone
+}
+
+/// `undo` from `backup_dir`, copying its main database out to `tmp` first. `tmp` is left behind on errors
+fn undo_with(
+    task_dir: &Path,
+    main_db_path: &Path,
+    tmp: &Path,
+    backup_dir: &Path,
+    state_dir: &Path,
+    config: &Config,
+    force: bool,
+) -> Result<()> {
+    let mut backed_up = None;
+    backup::read_files(backup_dir, |name, modified, contents| {
+        if name == MAIN_DB_NAME {
+            io::copy(contents, &mut fs::File::create(tmp)?)
+                .wrap_err_with(|| format!("Unable to write {}", tmp.display()))?;
+            backed_up = Some(modified);
+        }
+        Ok(())
+    })
+    .wrap_err_with(|| format!("Unable to undo from {}", backup_dir.display()))?;
+    let Some(backed_up) = backed_up else {
+        bail!(
+            "Backup {} has no main database to restore, use 'restore' to copy its conflict files back instead",
+            backup_dir.display()
+        );
+    };
+
+    let recorded = report::read_checksums(backup_dir)?;
+    let restored_checksum = sha256::digest_file(tmp)?;
+    backup::check(MAIN_DB_NAME, &restored_checksum, &recorded.checksums)
+        .wrap_err_with(|| format!("Refusing to undo from {}", backup_dir.display()))?;
+
+    if main_db_path.is_file() {
+        // The restored main db must not be read together with the write-ahead log of the one it replaces, and changes
+        // taskwarrior hasn't checkpointed yet would otherwise not show up in the checksum
+        checkpoint(task_dir)?;
+
+        // Anything but the merged database the resolve wrote means taskwarrior has used it since
+        let modified = match &recorded.merged_checksum {
+            Some(merged_checksum) => {
+                let current = sha256::digest_file(main_db_path)
+                    .wrap_err_with(|| format!("Unable to read {}", main_db_path.display()))?;
+                current != *merged_checksum
+            }
+            // Backups from before the merged database's checksum was recorded only have the times to go by
+            None => fs::metadata(main_db_path)?.modified()? > backed_up,
+        };
+        if modified && !force {
+            warn!(
+                "{} was modified after the backup in {} was made, undoing would lose those changes",
+                main_db_path.display(),
+                backup_dir.display()
+            );
+            bail!("Refusing to undo, pass --force to undo anyway");
+        }
+
+        // Like a restore, the database being replaced gets a backup of its own
+        back_up_replaced(state_dir, config, &[main_db_path], Some(&restored_checksum)).wrap_err(
+            "Unable to back up the main database the undo would replace, nothing was undone",
+        )?;
+    }
+
+    info!(
+        "Restoring {} from {}",
+        main_db_path.display(),
+        backup_dir.display()
+    );
+    fsutil::replace_atomically(tmp, main_db_path)?;
+
+    Ok(())
+}
+
+/// Checkpoint the main database's write-ahead log, and fail if it is still there afterwards
+fn checkpoint(task_dir: &Path) -> Result<()> {
+    let wal_path = crate::wal_path(&task_dir.join(MAIN_DB_NAME));
+    if !wal_path.is_file() {
+        return Ok(());
+    }
+    debug!("Checkpointing {}", wal_path.display());
+    taskwarrior::checkpoint(task_dir)?;
+    if wal_path.is_file() {
+        bail!(
+            "{} is still present after checkpointing it, taskwarrior may be running. Nothing was changed, try again \
+             once it has exited",
+            wal_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Back up the files about to be `replaced` into a new backup dir. `main_checksum` is the checksum of the main
+/// database that replaces the current one, so a later `undo` can tell whether it has been used since
+fn back_up_replaced(
+    state_dir: &Path,
+    config: &Config,
+    replaced: &[&Path],
+    main_checksum: Option<&str>,
+) -> Result<()> {
+    if replaced.is_empty() {
+        return Ok(());
+    }
+    let now = Zoned::now().with_time_zone(config.backup_timezone()?);
+    let before = Backup {
+        dir: state_dir.join(backup::backup_name(&now, config.backup_date_format())),
+        compress: config.compress_backups.unwrap_or(false),
+    };
+    if before.dir.exists() {
+        bail!(
+            "A backup named {} already exists, try again in a second",
+            before.dir.display()
+        );
+    }
+    let checksums = before.save("databases", replaced)?;
+    report::add_checksums(&before.dir, &checksums, main_checksum)?;
+    info!(
+        "Backed up the files being replaced to {}",
+        before.dir.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    struct Dirs {
+        _root: tempfile::TempDir,
+        task_dir: PathBuf,
+        state_dir: PathBuf,
+    }
+
+    fn dirs() -> Dirs {
+        let root = tempfile::tempdir().unwrap();
+        let task_dir = root.path().join("task");
+        let state_dir = root.path().join("state");
+        fs::create_dir(&task_dir).unwrap();
+        fs::create_dir(&state_dir).unwrap();
+        Dirs {
+            _root: root,
+            task_dir,
+            state_dir,
+        }
+    }
+
+    fn checksum(contents: &[u8]) -> String {
+        sha256::of_reader(&mut &contents[..]).unwrap()
+    }
+
+    /// A backup of a main database with `contents`, made by a resolve that wrote one with `merged`
+    fn backup(dirs: &Dirs, config: &Config, contents: &[u8], merged: Option<&[u8]>) -> PathBuf {
+        let when: Zoned = "2024-01-01T00:00:00+00:00[UTC]".parse().unwrap();
+        let dir = dirs
+            .state_dir
+            .join(backup::backup_name(&when, config.backup_date_format()));
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join(MAIN_DB_NAME), contents).unwrap();
+        let checksums = BTreeMap::from([(MAIN_DB_NAME.to_owned(), checksum(contents))]);
+        report::add_checksums(&dir, &checksums, merged.map(checksum).as_deref()).unwrap();
+        dir
+    }
+
+    /// The file names in the task dir
+    fn task_dir_names(dirs: &Dirs) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(&dirs.task_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn forced_undo_backs_up_the_main_db_it_replaces() {
+        let dirs = dirs();
+        let config = Config::default();
+        let main_db_path = dirs.task_dir.join(MAIN_DB_NAME);
+        backup(&dirs, &config, b"before", Some(b"merged"));
+        fs::write(&main_db_path, b"used since").unwrap();
+
+        assert!(undo(&dirs.task_dir, &dirs.state_dir, &config, false).is_err());
+        assert_eq!(fs::read(&main_db_path).unwrap(), b"used since");
+        assert_eq!(task_dir_names(&dirs), [MAIN_DB_NAME]);
+
+        undo(&dirs.task_dir, &dirs.state_dir, &config, true).unwrap();
+        assert_eq!(fs::read(&main_db_path).unwrap(), b"before");
+        assert_eq!(task_dir_names(&dirs), [MAIN_DB_NAME]);
+        let (_, newest) = backup::list(&dirs.state_dir, config.backup_date_format())
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(fs::read(newest.join(MAIN_DB_NAME)).unwrap(), b"used since");
+        let recorded = report::read_checksums(&newest).unwrap();
+        assert_eq!(recorded.merged_checksum, Some(checksum(b"before")));
+    }
+
+    #[test]
+    fn undo_refuses_a_write_ahead_log_it_cannot_checkpoint() {
+        let dirs = dirs();
+        let config = Config::default();
+        let main_db_path = dirs.task_dir.join(MAIN_DB_NAME);
+        // No merged checksum, so the undo only has the times to go by, and --force skips even that
+        backup(&dirs, &config, b"before", None);
+        fs::write(&main_db_path, b"not a database").unwrap();
+        fs::write(crate::wal_path(&main_db_path), b"stale log").unwrap();
+
+        assert!(undo(&dirs.task_dir, &dirs.state_dir, &config, true).is_err());
+        assert_eq!(fs::read(&main_db_path).unwrap(), b"not a database");
+        assert!(!fsutil::tmp_sibling(&main_db_path).exists());
+        assert_eq!(
+            backup::list(&dirs.state_dir, config.backup_date_format())
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn undo_from_a_corrupted_backup_leaves_nothing_behind() {
+        let dirs = dirs();
+        let config = Config::default();
+        let main_db_path = dirs.task_dir.join(MAIN_DB_NAME);
+        let backup_dir = backup(&dirs, &config, b"before", Some(b"merged"));
+        fs::write(backup_dir.join(MAIN_DB_NAME), b"bit-rotted").unwrap();
+        fs::write(&main_db_path, b"merged").unwrap();
+
+        assert!(undo(&dirs.task_dir, &dirs.state_dir, &config, true).is_err());
+        assert_eq!(fs::read(&main_db_path).unwrap(), b"merged");
+        assert_eq!(task_dir_names(&dirs), [MAIN_DB_NAME]);
+    }
+}
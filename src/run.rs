@@ -0,0 +1,1350 @@
+//! A whole resolve run, the way the command line asks for it: finding the conflict files, picking those to merge,
+//! the confirmation prompt, applying the merge, and reporting on it.
+
+use crate::backup::{self, Backup};
+use crate::config::{Backend, Config, DEFAULT_CONFLICT_LOOP_THRESHOLD, DEFAULT_KEEP_NUM};
+use crate::explain;
+use crate::fsutil;
+use crate::glob::Glob;
+use crate::lock::Lock;
+use crate::loops::Streaks;
+use crate::metrics;
+use crate::prompt;
+use crate::report::{self, Report};
+use crate::resolved::{self, Resolved};
+use crate::signal;
+use crate::target::{self, Target};
+use crate::temp;
+use crate::{
+    audit, diff, hook, legacy, notify, taskwarrior, ApplyOptions, Conflict, ResolveOptions, Source,
+    MAIN_DB_NAME,
+};
+use clap::{Args, ValueEnum, ValueHint};
+use color_eyre::{eyre::bail, eyre::WrapErr, Result};
+use jiff::{civil::DateTime, Span, Timestamp, Zoned};
+use owo_colors::OwoColorize;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufWriter, IsTerminal};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use task_hookrs::task::Task;
+use tracing::{debug, info, info_span, warn};
+use uuid::Uuid;
+
+/// Exit code when conflicts were found and resolved
+pub const EXIT_RESOLVED: u8 = 10;
+
+/// Exit code when conflicts were found, but left alone because of --dry-run
+pub const EXIT_NOT_APPLIED: u8 = 11;
+
+/// Progress bars are drawn when merging more databases than this
+const PROGRESS_THRESHOLD: usize = 10;
+
+#[derive(Debug, Args)]
+pub struct ResolveArgs {
+    /// Do not actually make changes, only report what would happen
+    #[clap(short, long)]
+    pub dry_run: bool,
+
+    /// Make the changes even though `dry_run` is set in the config
+    #[clap(long, visible_alias = "no-dry-run", conflicts_with = "dry_run")]
+    pub apply: bool,
+
+    /// With --dry-run, show the changes as taskwarrior sees them: write the merged database to a scratch dir, and
+    /// print a unified diff of `task export` on the main database and on it. Needs the `task` binary
+    #[clap(long, conflicts_with = "output")]
+    pub against_live: bool,
+
+    /// Print the merged tasks to stdout, one JSON object per line as `task import` reads them, instead of writing
+    /// them (e.g. `syncthing-task-resolve --emit-ndjson | task import`). The task dir and the state dir are left
+    /// untouched
+    #[clap(long, conflicts_with_all = ["dry_run", "review_out", "explain", "hook", "output", "print_backup_dir"])]
+    pub emit_ndjson: bool,
+
+    /// Number of backups to keep in the state dir, overriding `keep` from the config. Backups older than `keep_days`
+    /// are still removed
+    #[clap(short, long)]
+    pub keep: Option<usize>,
+
+    /// Only merge conflict files whose timestamp is at or after this time (e.g. 2024-01-02 or 2024-01-02T15:04:05).
+    /// The main database is always included
+    #[clap(long)]
+    pub since: Option<DateTime>,
+
+    /// Merge and back up the conflict files, but leave them on disk instead of removing them, overriding
+    /// `delete_conflicts` from the config. The next run finds them again, and leaves them alone as already resolved
+    #[clap(long)]
+    pub keep_conflicts: bool,
+
+    /// Run even though the state dir is inside the task dir, where syncthing would sync the backups as well
+    #[clap(long)]
+    pub allow_nested_state: bool,
+
+    /// Leave conflict files modified less than this long ago (e.g. 30s, or "5 minutes") alone, since syncthing may
+    /// still be writing them: they are not merged, and not deleted
+    #[clap(long, value_name = "DURATION")]
+    pub min_file_age: Option<Span>,
+
+    /// Rebuild the main database from the conflict copies alone, leaving out the tasks in the main database (e.g.
+    /// because it is corrupted). It is still backed up before it is replaced
+    #[clap(long, conflicts_with_all = ["task_filter", "use_context"])]
+    pub ignore_main: bool,
+
+    /// Read this many databases at once, overriding `parallelism` from the config. Defaults to one per CPU, 1 reads them
+    /// one after the other
+    #[clap(long, value_name = "N")]
+    pub parallel: Option<NonZeroUsize>,
+
+    /// Only merge the newest N conflict files from each device. The older ones are still backed up and deleted
+    #[clap(long, value_name = "N")]
+    pub limit_devices: Option<NonZeroUsize>,
+
+    /// Only merge the tasks matching this taskwarrior filter (e.g. 'project:work'). Other tasks in the main database
+    /// are kept as they are, and the conflict files are left on disk to be fully resolved later
+    #[clap(long, value_name = "QUERY")]
+    pub task_filter: Option<String>,
+
+    /// Only merge the tasks in taskwarrior's active context, using its read filter like --task-filter. Together with
+    /// --task-filter, only the tasks matching both are merged
+    #[clap(long)]
+    pub use_context: bool,
+
+    /// Leave conflict files whose name matches this glob (e.g. '*-ABCDEFG.sqlite3') alone: they are not merged, and
+    /// not deleted. Can be given several times
+    #[clap(long, value_name = "GLOB")]
+    pub exclude: Vec<Glob>,
+
+    /// Write the merged database to this directory instead, for a look before trusting the merge (e.g. with
+    /// `TASKDATA=<DIR> task list`). The task dir and the state dir are left untouched
+    #[clap(long, value_name = "DIR", value_hint = ValueHint::DirPath, conflicts_with = "dry_run")]
+    pub review_out: Option<PathBuf>,
+
+    /// Show every snapshot of this task across the databases, and how the merge picked between them, without changing
+    /// anything
+    #[clap(long, value_name = "UUID", conflicts_with_all = ["dry_run", "review_out", "hook", "output", "print_backup_dir", "metrics_file"])]
+    pub explain: Option<Uuid>,
+
+    /// Write the merge result even when it doesn't have exactly one task for every task read from the databases
+    #[clap(long)]
+    pub no_verify: bool,
+
+    /// Delete the conflict files without backing up any databases to the state dir, overriding `backup` from the
+    /// config
+    #[clap(long)]
+    pub no_backup: bool,
+
+    /// Don't print the colored summary to stderr after resolving
+    #[clap(short, long)]
+    pub quiet: bool,
+
+    /// Don't ask for confirmation before changing anything. There is no prompt when stdin is not a terminal anyway
+    #[clap(short, long)]
+    pub yes: bool,
+
+    /// Write metrics for node_exporter's textfile collector to this file (e.g. /var/lib/node_exporter/str.prom).
+    /// Counters continue from the values already in the file
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    pub metrics_file: Option<PathBuf>,
+
+    /// Fail instead of only warning when the taskwarrior version is outside the range the merge is known to work
+    /// with
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Run as taskwarrior's on-launch hook, with the arguments taskwarrior passes to hooks (e.g. `data:<DIR>`), see the
+    /// README. Exits with 0 even on errors, so taskwarrior still runs
+    #[clap(long, value_name = "HOOK_ARG", num_args = 0.., allow_hyphen_values = true)]
+    pub hook: Option<Vec<String>>,
+
+    /// Format of the summary printed to stdout after resolving
+    #[clap(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+
+    /// Print only the absolute path of the backup dir to stdout, or nothing when there was nothing to resolve (e.g.
+    /// for `cp -r "$(syncthing-task-resolve --print-backup-dir)" /mnt/elsewhere`)
+    #[clap(long, conflicts_with_all = ["output", "dry_run", "review_out", "hook", "no_backup"])]
+    pub print_backup_dir: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// No summary, only log messages
+    Text,
+    /// A JSON object describing the conflicts found and what was done about them
+    Json,
+}
+
+/// Machine-readable record of a resolve run
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    /// False in dry-run and review mode, when there was nothing to resolve, or when the confirmation prompt was
+    /// declined
+    pub applied: bool,
+
+    /// Whether the confirmation prompt was answered with no
+    pub declined: bool,
+    pub num_conflicts: usize,
+    pub conflicts: Vec<Conflict>,
+    pub merged_tasks: usize,
+
+    /// Merged tasks whose copies actually differed between databases
+    pub conflicted_tasks: usize,
+    pub backup_dir: Option<PathBuf>,
+
+    /// With --review-out, the directory the merged database was written to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub review_dir: Option<PathBuf>,
+
+    /// In a dry run, the merged tasks that would be imported, in `task import` format
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tasks: Option<Vec<Task>>,
+
+    /// Conflict files with nothing to merge, because they are identical to the main database or were already
+    /// resolved. They are removed along with the others
+    pub redundant: Vec<PathBuf>,
+}
+
+impl Summary {
+    pub fn new(conflicts: &[Conflict], read_only: bool) -> Self {
+        Self {
+            applied: !read_only && !conflicts.is_empty(),
+            declined: false,
+            num_conflicts: conflicts.len(),
+            conflicts: conflicts.to_vec(),
+            merged_tasks: 0,
+            conflicted_tasks: 0,
+            backup_dir: None,
+            review_dir: None,
+            tasks: None,
+            redundant: Vec::new(),
+        }
+    }
+
+    /// Print a short, colored description of what happened to stderr
+    pub fn print(&self, task_dir: &Path) {
+        let plural = |n: usize, word: &str| match n {
+            1 => format!("{} {}", n, word),
+            _ => format!("{} {}s", n, word),
+        };
+
+        if self.num_conflicts == 0 && self.redundant.is_empty() {
+            eprintln!("{} in {}", "No conflict files".green(), task_dir.display());
+            return;
+        }
+
+        let files = plural(self.num_conflicts, "conflict file");
+        let tasks = format!(
+            "{} ({} conflicted)",
+            plural(self.merged_tasks, "task"),
+            self.conflicted_tasks
+        );
+        if self.applied {
+            eprintln!(
+                "{} {} in {}, merged {}",
+                "Resolved".green().bold(),
+                files,
+                task_dir.display(),
+                tasks
+            );
+        } else if let Some(dir) = &self.review_dir {
+            eprintln!(
+                "{} found {} in {}, merged {} into {}",
+                "Review:".yellow().bold(),
+                files,
+                task_dir.display(),
+                tasks,
+                dir.display()
+            );
+        } else {
+            let label = match self.declined {
+                true => "Not applied:",
+                false => "Dry run:",
+            };
+            eprintln!(
+                "{} found {} in {}, would merge {}",
+                label.yellow().bold(),
+                files,
+                task_dir.display(),
+                tasks
+            );
+        }
+        if !self.redundant.is_empty() {
+            let verb = match self.applied {
+                true => "removed",
+                false => "would remove",
+            };
+            eprintln!(
+                "  {} {} with nothing to merge",
+                format!("{}:", verb).dimmed(),
+                plural(self.redundant.len(), "conflict file")
+            );
+        }
+        if let Some(dir) = &self.backup_dir {
+            eprintln!("  {} {}", "backup:".dimmed(), dir.display());
+        }
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        if self.num_conflicts == 0 {
+            ExitCode::SUCCESS
+        } else if self.applied {
+            ExitCode::from(EXIT_RESOLVED)
+        } else {
+            ExitCode::from(EXIT_NOT_APPLIED)
+        }
+    }
+}
+
+/// Print each detected conflict file, one per line
+pub fn list(task_dir: &Path, config: &Config) -> Result<()> {
+    target::check_task_dir(task_dir)?;
+    let mut conflicts = crate::detect_conflicts(task_dir, config)?;
+    conflicts.sort_by_key(|conflict| conflict.timestamp);
+    for conflict in &conflicts {
+        println!(
+            "{}\t{}\t{}",
+            conflict.timestamp,
+            conflict.source.device(),
+            conflict.path.display()
+        );
+    }
+    Ok(())
+}
+
+/// The taskwarrior binary, its version, and the run's temporary directory (see `temp::run_dir`), checking everything
+/// resolving needs that is better found out now than after reading all the databases
+pub fn prepare_resolve(
+    task_bin_arg: Option<&Path>,
+    config: &mut Config,
+    args: &ResolveArgs,
+) -> Result<(PathBuf, Option<String>, tempfile::TempDir)> {
+    // Both run taskwarrior, whichever backend reads and writes the databases
+    let needed = config.uses_task_bin() || args.use_context || args.against_live;
+    let task_bin = taskwarrior::task_bin(task_bin_arg, config, needed)?;
+    let run_temp = temp::run_dir(config)?;
+    let task_version = match config.uses_task_bin() {
+        true => taskwarrior::check_version(&task_bin, args.strict)?,
+        false => None,
+    };
+    Ok((task_bin, task_version, run_temp))
+}
+
+/// Resolve each of `targets`, returning the exit code for the whole run
+pub fn resolve_targets(
+    task_bin: &Path,
+    task_version: Option<&str>,
+    targets: &[Target],
+    config: &Config,
+    args: &ResolveArgs,
+) -> Result<ExitCode> {
+    let mut exit_code = ExitCode::SUCCESS;
+    let mut runs = Vec::new();
+    for target in targets {
+        let _span = info_span!("task_dir", path = %target.task_dir.display()).entered();
+        let summary = resolve(
+            task_bin,
+            task_version,
+            &target.task_dir,
+            &target.state_dir,
+            config,
+            args,
+        )?;
+        if summary.num_conflicts > 0 {
+            exit_code = summary.exit_code();
+        }
+        if args.metrics_file.is_some() {
+            let (conflicts, merged_tasks) = match summary.applied {
+                true => (summary.num_conflicts, summary.merged_tasks),
+                false => (0, 0),
+            };
+            runs.push(metrics::Run {
+                task_dir: &target.task_dir,
+                conflicts,
+                merged_tasks,
+                backup_dirs: backup::list(&target.state_dir, config.backup_date_format())?.len(),
+            });
+        }
+        if let Some(name) = signal::received() {
+            bail!(
+                "Received {}, stopping after {}",
+                name,
+                target.task_dir.display()
+            );
+        }
+    }
+    if let Some(metrics_file) = &args.metrics_file {
+        metrics::write(metrics_file, &runs, Timestamp::now())?;
+    }
+    Ok(exit_code)
+}
+
+/// Resolve the conflict files in `task_dir` as the command line options in `args` ask, with the backups going to
+/// `state_dir`
+pub fn resolve(
+    task_bin: &Path,
+    task_version: Option<&str>,
+    task_dir: &Path,
+    state_dir: &Path,
+    config: &Config,
+    args: &ResolveArgs,
+) -> Result<Summary> {
+    // The config's default only stands in for --dry-run, the other read-only runs already don't change anything
+    let dry_run = args.dry_run
+        || (!args.apply
+            && config.dry_run.unwrap_or(false)
+            && args.review_out.is_none()
+            && args.explain.is_none()
+            && !args.emit_ndjson);
+    if args.against_live && !dry_run {
+        bail!("--against-live only works together with --dry-run");
+    }
+
+    // Neither a dry run, a review, an explanation, nor emitting the tasks changes anything in the task dir or the
+    // state dir
+    let read_only =
+        dry_run || args.review_out.is_some() || args.explain.is_some() || args.emit_ndjson;
+
+    // The context is read from the taskrc, the same for every task dir, but through the task dir like everything else
+    let context_filter = match args.use_context {
+        true => taskwarrior::context_filter(task_bin, task_dir)?,
+        false => None,
+    };
+    let task_filter = match (&args.task_filter, context_filter) {
+        (Some(filter), Some(context)) => Some(format!("( {} ) and ( {} )", filter, context)),
+        (filter, context) => context.or(filter.clone()),
+    };
+    if task_filter.is_some() && config.read_backend.unwrap_or_default() == Backend::Native {
+        bail!(
+            "--task-filter and --use-context need taskwarrior to filter the tasks, set read_backend = \"cli\" to use them"
+        );
+    }
+
+    target::check_task_dir(task_dir)?;
+
+    // Syncthing would pick up the backups too, and a backup that ends up conflicting is merged back into the main db
+    let nested = fsutil::is_within(state_dir, task_dir).wrap_err_with(|| {
+        format!(
+            "Unable to tell whether {} is inside {}",
+            state_dir.display(),
+            task_dir.display()
+        )
+    })?;
+    if nested && !args.allow_nested_state {
+        bail!(
+            "The state dir {} is inside the task dir {}, so syncthing would sync the backups, and their copies could come \
+             back as conflict files. Set state_dir to somewhere outside of it, or pass --allow-nested-state",
+            state_dir.display(),
+            task_dir.display()
+        );
+    }
+
+    // Hold the lock for the rest of the run so a second instance can't move the same files underneath us.
+    // A read-only run doesn't change anything, so it doesn't need to exclude others.
+    let _lock = match read_only {
+        true => None,
+        false => Some(Lock::acquire(state_dir)?),
+    };
+
+    let mut conflicts = crate::detect_conflicts(task_dir, config)?;
+    let delete_conflicts = !args.keep_conflicts && config.delete_conflicts.unwrap_or(true);
+
+    // A conflict file syncthing is still writing would be merged half done, and then deleted
+    let settled_before = match args.min_file_age {
+        Some(span) => Some(
+            Zoned::now()
+                .checked_sub(span)
+                .wrap_err_with(|| format!("Unable to go back {} from now", span))?
+                .timestamp(),
+        ),
+        None => None,
+    };
+    let is_settled = |conflict: &Conflict| {
+        let Some(settled_before) = settled_before else {
+            return true;
+        };
+        // Files that can't be looked at are reported by the checks after this one
+        let Ok(modified) = fs::metadata(&conflict.path).and_then(|metadata| metadata.modified())
+        else {
+            return true;
+        };
+        let settled = Timestamp::try_from(modified).is_ok_and(|modified| modified < settled_before);
+        if !settled {
+            info!(
+                "Skipping {}, it was modified within --min-file-age and may still be syncing",
+                conflict.path.display()
+            );
+        }
+        settled
+    };
+    conflicts.retain(is_settled);
+
+    // Partially synced or truncated copies would fail to read or pollute the merge. Leave them on disk for syncthing
+    // to finish, or for the user to look at
+    conflicts.retain(|Conflict { path, source, .. }| {
+        match crate::is_sqlite_db(path) {
+            Ok(true) => true,
+            Ok(false) => {
+                warn!(
+                    "Skipping {} ({}), it is not a valid SQLite database (it may not be fully synced yet, or is damaged)",
+                    path.display(),
+                    source
+                );
+                false
+            }
+            Err(e) => {
+                warn!("Skipping {} ({}): {}", path.display(), source, e);
+                false
+            }
+        }
+    });
+
+    // Syncthing sometimes leaves a conflict copy that is identical to the main database. It has nothing to merge, and
+    // would only make another device show up as a source of tasks. Such copies are only removed once the merge is
+    // applied, along with the other conflict files
+    let main_db_path = task_dir.join(MAIN_DB_NAME);
+    let mut redundant = Vec::new();
+    if main_db_path.is_file() {
+        conflicts.retain(|conflict| {
+            match fsutil::same_contents(&conflict.path, &main_db_path) {
+                Ok(false) => return true,
+                Ok(true) => {}
+                Err(e) => {
+                    warn!(
+                        "Unable to compare {} to the main database: {}",
+                        conflict.path.display(),
+                        e
+                    );
+                    return true;
+                }
+            }
+            if !delete_conflicts {
+                info!(
+                    "Skipping {} ({}), it is identical to the main database",
+                    conflict.path.display(),
+                    conflict.source
+                );
+                return false;
+            }
+            info!(
+                "Would remove {} ({}), it is identical to the main database",
+                conflict.path.display(),
+                conflict.source
+            );
+            redundant.push(conflict.clone());
+            false
+        });
+    }
+
+    // Syncthing can bring back a conflict file after it was merged and removed. Merging it again would change nothing,
+    // so it is only removed again once the merge is applied. A file that was kept on purpose is just left alone
+    let mut already_resolved = Resolved::load(state_dir);
+    conflicts.retain(|conflict| {
+        match already_resolved.contains(&conflict.path) {
+            Ok(false) => return true,
+            Ok(true) => {}
+            Err(e) => {
+                warn!(
+                    "Unable to check whether {} was already resolved: {}",
+                    conflict.path.display(),
+                    e
+                );
+                return true;
+            }
+        }
+        if !delete_conflicts {
+            info!(
+                "Skipping {} ({}), it was already resolved",
+                conflict.path.display(),
+                conflict.source
+            );
+            return false;
+        }
+        info!(
+            "Would remove {} ({}), it was already resolved",
+            conflict.path.display(),
+            conflict.source
+        );
+        redundant.push(conflict.clone());
+        false
+    });
+
+    // taskwarrior 2.x data files can't be queried, so a filtered run leaves them alone
+    let mut legacy_conflicts = match config.legacy_format.unwrap_or(false) && task_filter.is_none()
+    {
+        true => legacy::detect_conflicts(task_dir, config.syncthing_date_format())?,
+        false => Vec::new(),
+    };
+    legacy_conflicts.retain(is_settled);
+
+    // Leave stale copies from before the sync event being looked at alone
+    if let Some(since) = args.since {
+        let is_recent = |conflict: &Conflict| {
+            let recent = conflict.timestamp >= since;
+            if !recent {
+                info!(
+                    "Skipping {}, it is older than {}",
+                    conflict.path.display(),
+                    since
+                );
+            }
+            recent
+        };
+        conflicts.retain(is_recent);
+        legacy_conflicts.retain(is_recent);
+    }
+
+    if !args.exclude.is_empty() {
+        let is_included = |conflict: &Conflict| {
+            let name = conflict.path.file_name().and_then(|name| name.to_str());
+            let glob = name.and_then(|name| args.exclude.iter().find(|glob| glob.is_match(name)));
+            if let Some(glob) = glob {
+                info!(
+                    "Skipping {}, it matches --exclude '{}'",
+                    conflict.path.display(),
+                    glob
+                );
+            }
+            glob.is_none()
+        };
+        conflicts.retain(is_included);
+        legacy_conflicts.retain(is_included);
+    }
+
+    drop_duplicates(&mut conflicts);
+
+    let all_conflicts: Vec<Conflict> = conflicts.iter().chain(&legacy_conflicts).cloned().collect();
+    let mut summary = Summary::new(&all_conflicts, read_only);
+    summary.redundant = redundant
+        .iter()
+        .map(|conflict| conflict.path.clone())
+        .collect();
+    if !read_only && !redundant.is_empty() {
+        summary.applied = true;
+    }
+
+    // Create a dir to back up conflicted task DBs to prevent data loss
+    let backup = !args.no_backup && config.backup.unwrap_or(true);
+    let action_history =
+        if backup && !read_only && (!all_conflicts.is_empty() || !redundant.is_empty()) {
+            let now = Zoned::now().with_time_zone(config.backup_timezone()?);
+            Some(Backup {
+                dir: state_dir.join(backup::backup_name(&now, config.backup_date_format())),
+                compress: config.compress_backups.unwrap_or(false),
+            })
+        } else {
+            None
+        };
+    summary.backup_dir = action_history.as_ref().map(|backup| backup.dir.clone());
+
+    if args.explain.is_some() && conflicts.is_empty() {
+        println!(
+            "No conflict files in {}, so nothing is merged",
+            task_dir.display()
+        );
+        return Ok(Summary::new(&[], read_only));
+    }
+
+    // Only perform operations if there are conflicts
+    let sqlite_merge = if conflicts.is_empty() {
+        None
+    } else {
+        // Also add the main db to list of conflicts, so it is part of our history merging
+        let main_db_exists = main_db_path.is_file();
+        if main_db_exists {
+            let metadata = fs::metadata(&main_db_path)?;
+            let modified = metadata.modified()?;
+            let timestamp = Zoned::try_from(modified)?;
+            let timestamp = DateTime::from(timestamp);
+            conflicts.push(Conflict {
+                path: main_db_path.clone(),
+                source: Source::MainDb,
+                timestamp,
+            });
+        }
+
+        // Changes taskwarrior hasn't checkpointed yet only exist in the write-ahead log, which a plain copy of the db
+        // would miss
+        let wal_path = crate::wal_path(&main_db_path);
+        if main_db_exists && wal_path.is_file() {
+            if read_only {
+                debug!(
+                    "{} exists, reading it along with the main db",
+                    wal_path.display()
+                );
+            } else {
+                debug!("Checkpointing {}", wal_path.display());
+                taskwarrior::checkpoint(task_dir)?;
+                // SQLite would replay a stale log into the merged db that replaces the main one, and corrupt it
+                if wal_path.is_file() {
+                    bail!(
+                        "{} is still present after checkpointing it, taskwarrior may be running. Nothing was changed, \
+                         resolve again once it has exited",
+                        wal_path.display()
+                    );
+                }
+            }
+        }
+
+        // Sort by timestamp
+        conflicts.sort_by_key(|conflict| conflict.timestamp);
+
+        // The older copies are still backed up and removed along with the others, they are just not read
+        let mut to_merge = match args.limit_devices {
+            Some(limit) => newest_per_device(&conflicts, limit.get()),
+            None => conflicts.clone(),
+        };
+        // The main db is still backed up and replaced, its tasks just don't take part
+        if args.ignore_main {
+            info!(
+                "Ignoring the tasks in {}, merging only the conflict copies",
+                main_db_path.display()
+            );
+            to_merge.retain(|conflict| conflict.source != Source::MainDb);
+        }
+
+        // The db that the merge result is compared against. Without a main db (e.g. syncthing only delivered conflict
+        // copies to a fresh machine), the newest conflict copy stands in for it
+        let base_db_path = if main_db_exists {
+            main_db_path.clone()
+        } else {
+            let newest = conflicts.last().expect("there is at least one conflict");
+            warn!(
+                "Main database {} does not exist, synthesizing it from the newest conflict copy {} ({})",
+                main_db_path.display(),
+                newest.path.display(),
+                newest.source
+            );
+            newest.path.clone()
+        };
+
+        let opts = ResolveOptions {
+            task_bin,
+            read_backend: config.read_backend.unwrap_or_default(),
+            merge_strategy: config.merge_strategy.unwrap_or_default(),
+            sticky_completion: config.sticky_completion.unwrap_or(false),
+            winner_priority: config.winner_priority.as_deref().unwrap_or_default(),
+            task_filter: task_filter.as_deref(),
+            temp_dir: config.temp_dir.as_deref(),
+            parallelism: args.parallel.or(config.parallelism),
+            // A handful of databases is read too quickly for a bar to be of any use
+            progress: to_merge.len() > PROGRESS_THRESHOLD && !args.quiet && args.hook.is_none(),
+            verify: !args.no_verify,
+        };
+        if let Some(uuid) = &args.explain {
+            explain::explain(&to_merge, &opts, uuid)?;
+            return Ok(Summary::new(&[], read_only));
+        }
+        let result = crate::resolve(&to_merge, &opts)?;
+        // Reads the databases as a whole, for comparing the merge result with them
+        let unfiltered = ResolveOptions {
+            task_filter: None,
+            ..opts
+        };
+        let merged = result.merged;
+        summary.merged_tasks = merged.len();
+        summary.conflicted_tasks = result.conflicted_uuids.len();
+        info!(
+            "{} tasks, {} of which were conflicted across devices ({} only in one database)",
+            merged.len(),
+            result.conflicted_uuids.len(),
+            result.unique_uuids
+        );
+
+        // Taskwarrior shows all of stdout as the hook's feedback, which is kept to one line
+        if args.output == OutputFormat::Text
+            && args.hook.is_none()
+            && !args.print_backup_dir
+            && !args.emit_ndjson
+        {
+            // Report where each conflicting task's winning version came from
+            for m in merged.iter().filter(|m| m.conflicted) {
+                debug!(
+                    "Task {} taken from {}",
+                    m.task.uuid(),
+                    m.winner.path.display()
+                );
+                let modified = m.winner.task.modified().unwrap_or(m.winner.task.entry());
+                println!(
+                    "task {}: kept version from {} ({})",
+                    m.task.uuid(),
+                    m.winner.source,
+                    modified.format("%Y-%m-%d %H:%M:%S")
+                );
+            }
+        }
+        let mut tasks: Vec<Task> = merged.iter().map(|m| m.task.clone()).collect();
+
+        // The merged db replaces the main one, so the tasks the filter left out have to be carried over as they are
+        if task_filter.is_some() {
+            let merged_uuids: HashSet<Uuid> = tasks.iter().map(|task| *task.uuid()).collect();
+            let unmatched: Vec<Task> = crate::read_database(&unfiltered, &base_db_path)?
+                .into_iter()
+                .filter(|task| !merged_uuids.contains(task.uuid()))
+                .collect();
+            info!(
+                "Keeping {} tasks that don't match the filter as they are",
+                unmatched.len()
+            );
+            tasks.extend(unmatched);
+        }
+
+        if dry_run && args.output == OutputFormat::Text && args.against_live {
+            print!("{}", live_diff(task_bin, config, &main_db_path, &tasks)?);
+        } else if dry_run && args.output == OutputFormat::Text {
+            // Show what would change in the main db
+            let current: HashMap<Uuid, Task> = crate::read_database(&unfiltered, &base_db_path)?
+                .into_iter()
+                .map(|task| (*task.uuid(), task))
+                .collect();
+            for task in &tasks {
+                diff::print_task_diff(current.get(task.uuid()), task)?;
+            }
+        }
+
+        if dry_run && args.output == OutputFormat::Json {
+            summary.tasks = Some(tasks.clone());
+        }
+
+        if args.emit_ndjson {
+            taskwarrior::write_ndjson(BufWriter::new(io::stdout().lock()), &tasks)
+                .wrap_err("Unable to write the merged tasks to stdout")?;
+        }
+
+        Some((main_db_path, merged, tasks))
+    };
+
+    let legacy_merge = if legacy_conflicts.is_empty() {
+        None
+    } else if args.emit_ndjson {
+        warn!(
+            "Leaving out {} legacy data file conflicts, --emit-ndjson only covers the taskchampion database",
+            legacy_conflicts.len()
+        );
+        None
+    } else {
+        let merged = legacy::merge(task_dir, &legacy_conflicts)?;
+        summary.merged_tasks += merged.merged_tasks;
+        summary.conflicted_tasks += merged.conflicted_tasks;
+        if dry_run && args.output == OutputFormat::Text {
+            println!(
+                "legacy data files: would merge {} tasks ({} conflicted) from {} conflict files",
+                merged.merged_tasks,
+                merged.conflicted_tasks,
+                legacy_conflicts.len()
+            );
+        }
+        Some(merged)
+    };
+
+    // Someone who ran the bare command to see what it does gets a chance to back out
+    if summary.applied && !args.yes && io::stdin().is_terminal() {
+        let question = match &task_filter {
+            Some(filter) if summary.redundant.is_empty() => format!(
+                "About to merge {} tasks matching '{}' into the main database in {}, continue?",
+                summary.merged_tasks,
+                filter,
+                task_dir.display()
+            ),
+            Some(filter) => format!(
+                "About to merge {} tasks matching '{}' into the main database and delete {} conflict files with \
+                 nothing to merge in {}, continue?",
+                summary.merged_tasks,
+                filter,
+                summary.redundant.len(),
+                task_dir.display()
+            ),
+            None if !delete_conflicts => format!(
+                "About to merge {} tasks from {} conflict files (leaving them on disk) in {}, continue?",
+                summary.merged_tasks,
+                summary.num_conflicts,
+                task_dir.display()
+            ),
+            None => format!(
+                "About to merge {} tasks and delete {} conflict files in {}, continue?",
+                summary.merged_tasks,
+                summary.num_conflicts + summary.redundant.len(),
+                task_dir.display()
+            ),
+        };
+        if !prompt::confirm(&question)? {
+            eprintln!("Aborted, nothing was changed");
+            summary.applied = false;
+            summary.declined = true;
+            summary.backup_dir = None;
+        }
+    }
+
+    let apply_opts = ApplyOptions {
+        task_bin,
+        write_backend: config.write_backend.unwrap_or_default(),
+        retry: config.retry(),
+        temp_dir: config.temp_dir.as_deref(),
+        delete_conflicts,
+    };
+
+    // The merge result goes to the review dir, where it can't clash with anything in the task dir
+    if let Some(review_dir) = &args.review_out {
+        if !all_conflicts.is_empty() {
+            if review_dir.join(MAIN_DB_NAME).exists() {
+                bail!(
+                    "{} already contains a {}, pick an empty directory for --review-out",
+                    review_dir.display(),
+                    MAIN_DB_NAME
+                );
+            }
+            fs::create_dir_all(review_dir)
+                .wrap_err_with(|| format!("Unable to create {}", review_dir.display()))?;
+            if let Some((_, _, tasks)) = &sqlite_merge {
+                crate::write_database(&apply_opts, tasks, review_dir)?;
+            }
+            if let Some(merged) = &legacy_merge {
+                legacy::write_to(review_dir, merged)?;
+            }
+            info!("Wrote the merge result to {}", review_dir.display());
+            summary.review_dir = Some(review_dir.clone());
+        }
+    }
+
+    // Everything above only read the task dir. From here on files are written, so a dry run stops here
+    if summary.applied {
+        if let Some((main_db_path, merged, tasks)) = &sqlite_merge {
+            let report = Report::new(
+                main_db_path,
+                config.merge_strategy.unwrap_or_default(),
+                task_version,
+                &summary.conflicts,
+                merged,
+            );
+            // Tasks outside the filter may still differ between the copies, so only the main db is replaced
+            let mut replaced: Vec<Conflict> = match task_filter {
+                Some(_) => conflicts
+                    .iter()
+                    .filter(|conflict| conflict.source == Source::MainDb)
+                    .cloned()
+                    .collect(),
+                None => conflicts.clone(),
+            };
+
+            // A filtered run only sees some of the tasks, which says nothing about whether the others conflicted
+            let mut streaks = match task_filter {
+                Some(_) => None,
+                None => Some(Streaks::load(state_dir)),
+            };
+            if let Some(streaks) = &mut streaks {
+                streaks.record(merged);
+                let threshold = config
+                    .conflict_loop_threshold
+                    .unwrap_or(DEFAULT_CONFLICT_LOOP_THRESHOLD);
+                let keep_looping = config.keep_looping_conflicts.unwrap_or(false);
+                for m in merged {
+                    let Some(streak) = streaks.get(m.task.uuid()) else {
+                        continue;
+                    };
+                    if streak.runs <= threshold {
+                        continue;
+                    }
+                    warn!(
+                        "Task {} ({}) has conflicted in {} resolves in a row, between {}. These devices may keep \
+                         overwriting each other's changes, resolving again won't stop that",
+                        m.task.uuid(),
+                        m.task.description(),
+                        streak.runs,
+                        streak.devices.join(", ")
+                    );
+                    if keep_looping {
+                        replaced.retain(|conflict| {
+                            let looping = conflict.source != Source::MainDb
+                                && m.origins.iter().any(|(_, path)| *path == conflict.path);
+                            if looping {
+                                info!(
+                                    "Leaving {} on disk, it has a snapshot of looping task {}",
+                                    conflict.path.display(),
+                                    m.task.uuid()
+                                );
+                            }
+                            !looping
+                        });
+                    }
+                }
+            }
+            // Taken before the files are removed
+            let entries: Vec<resolved::Entry> = replaced
+                .iter()
+                .filter(|conflict| conflict.source != Source::MainDb)
+                .filter_map(|conflict| match resolved::Entry::of(&conflict.path) {
+                    Ok(entry) => Some(entry),
+                    Err(e) => {
+                        warn!("Unable to hash {}: {}", conflict.path.display(), e);
+                        None
+                    }
+                })
+                .collect();
+            crate::apply(
+                &apply_opts,
+                tasks,
+                &replaced,
+                action_history.as_ref(),
+                &report,
+                main_db_path,
+            )?;
+            // The merge is done either way, so failing to remember it is not worth failing the run over
+            already_resolved.add(entries);
+            if let Err(e) = already_resolved.save(state_dir) {
+                warn!("Unable to record the resolved conflict files: {:#}", e);
+            }
+            if let Some(streaks) = &streaks {
+                if let Err(e) = streaks.save(state_dir) {
+                    warn!("Unable to record which tasks conflicted: {:#}", e);
+                }
+            }
+            if let Some(audit_log) = &config.audit_log {
+                if let Err(e) = audit::append(audit_log, merged, Timestamp::now()) {
+                    warn!("Unable to write the audit log: {:#}", e);
+                }
+            }
+        }
+        if let Some(merged) = &legacy_merge {
+            legacy::apply(
+                task_dir,
+                &legacy_conflicts,
+                merged,
+                action_history.as_ref(),
+                &apply_opts.retry,
+                delete_conflicts,
+            )?;
+        }
+        // Only once the merge is done, so declining or a failed merge leaves them in place like the others. They are
+        // backed up first all the same, in case the check that they have nothing to merge was wrong
+        if let (Some(backup), false) = (&action_history, redundant.is_empty()) {
+            let files: Vec<&Path> = redundant
+                .iter()
+                .map(|conflict| conflict.path.as_path())
+                .collect();
+            let checksums = apply_opts
+                .retry
+                .run("Backing up the files with nothing to merge", || {
+                    backup.save("redundant", &files)
+                })?;
+            apply_opts.retry.run(
+                "Recording the checksums of the files with nothing to merge",
+                || report::add_checksums(&backup.dir, &checksums, None),
+            )?;
+        }
+        for conflict in &redundant {
+            info!(
+                "Removing {} ({}), it has nothing to merge",
+                conflict.path.display(),
+                conflict.source
+            );
+            apply_opts
+                .retry
+                .run(&format!("Removing {}", conflict.path.display()), || {
+                    Ok(fs::remove_file(&conflict.path)?)
+                })?;
+        }
+    }
+
+    // Without backups there is nothing to prune
+    if summary.applied && backup {
+        let num_to_keep = args.keep.or(config.keep).unwrap_or(DEFAULT_KEEP_NUM);
+        backup::cleanup_state_dir(state_dir, config, num_to_keep)?;
+    }
+
+    if summary.applied {
+        if let Some(post_resolve_hook) = &config.post_resolve_hook {
+            hook::run(
+                post_resolve_hook,
+                task_dir,
+                summary.backup_dir.as_deref(),
+                summary.num_conflicts,
+            );
+        }
+    }
+
+    if args.output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    }
+
+    // The summary is for people watching; scripts get the exit code and --output json
+    if !args.quiet && !args.emit_ndjson && io::stdout().is_terminal() && io::stderr().is_terminal()
+    {
+        summary.print(task_dir);
+    }
+
+    if summary.applied && config.notify.unwrap_or(false) {
+        let body = match &summary.backup_dir {
+            Some(dir) => format!(
+                "Merged {} tasks from {} conflict files.\nBackup: {}",
+                summary.merged_tasks,
+                summary.num_conflicts,
+                dir.display()
+            ),
+            None => format!(
+                "Merged {} tasks from {} conflict files.",
+                summary.merged_tasks, summary.num_conflicts
+            ),
+        };
+        notify::send("Resolved taskwarrior sync conflicts", &body);
+    }
+
+    if args.print_backup_dir {
+        if let Some(dir) = &summary.backup_dir {
+            println!("{}", std::path::absolute(dir)?.display());
+        }
+    }
+
+    if summary.applied && args.hook.is_some() {
+        println!(
+            "Resolved {} syncthing conflict files, merging {} tasks",
+            summary.num_conflicts, summary.merged_tasks
+        );
+    }
+
+    Ok(summary)
+}
+
+/// What merging would change in the main database at `main_db_path`, as taskwarrior sees it: a unified diff of
+/// `task export` on a copy of it and on a scratch database with `tasks` written the way resolving would write them
+fn live_diff(
+    task_bin: &Path,
+    config: &Config,
+    main_db_path: &Path,
+    tasks: &[Task],
+) -> Result<String> {
+    let temp_dir = config.temp_dir.as_deref();
+    // Exported from a copy, so taskwarrior can't touch the live database even by opening it
+    let live = match main_db_path.is_file() {
+        true => {
+            let dir = crate::scratch_dir(temp_dir)?;
+            crate::copy_database(main_db_path, dir.path())?;
+            taskwarrior::export(task_bin, dir.path())?
+        }
+        false => Vec::new(),
+    };
+
+    let merged_dir = crate::scratch_dir(temp_dir)?;
+    let opts = ApplyOptions {
+        task_bin,
+        write_backend: config.write_backend.unwrap_or_default(),
+        retry: config.retry(),
+        temp_dir,
+        delete_conflicts: false,
+    };
+    crate::write_database(&opts, tasks, merged_dir.path())?;
+    let merged = taskwarrior::export(task_bin, merged_dir.path())?;
+
+    Ok(diff::unified_export_diff(
+        &live,
+        &merged,
+        &main_db_path.display().to_string(),
+        "merged",
+    ))
+}
+
+/// When syncthing races with itself, two conflict copies can have the same timestamp and device in their names. Only
+/// the one modified last is kept; the other is left on disk, to be merged by a later run once it is the only one
+fn drop_duplicates(conflicts: &mut Vec<Conflict>) {
+    // Newest first, so the copy seen first for a timestamp and device is the one to keep
+    conflicts.sort_by_cached_key(|conflict| {
+        let modified = fs::metadata(&conflict.path).and_then(|metadata| metadata.modified());
+        Reverse(modified.ok())
+    });
+
+    let mut kept: HashMap<(DateTime, Source), PathBuf> = HashMap::new();
+    conflicts.retain(|conflict| {
+        match kept.entry((conflict.timestamp, conflict.source.clone())) {
+            Entry::Occupied(entry) => {
+                warn!(
+                    "Skipping {}, it is a suspected duplicate of {} (same timestamp and device, modified earlier)",
+                    conflict.path.display(),
+                    entry.get().display()
+                );
+                false
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(conflict.path.clone());
+                true
+            }
+        }
+    });
+}
+
+/// The main db, and the newest `limit` of `conflicts` (sorted oldest first) from each device
+fn newest_per_device(conflicts: &[Conflict], limit: usize) -> Vec<Conflict> {
+    let mut per_device: HashMap<&Source, usize> = HashMap::new();
+    let mut newest: Vec<Conflict> = conflicts
+        .iter()
+        .rev()
+        .filter(|conflict| {
+            if conflict.source == Source::MainDb {
+                return true;
+            }
+            let seen = per_device.entry(&conflict.source).or_default();
+            *seen += 1;
+            if *seen > limit {
+                info!(
+                    "Not merging {}, it is not among the {} newest conflict files from {}",
+                    conflict.path.display(),
+                    limit,
+                    conflict.source
+                );
+            }
+            *seen <= limit
+        })
+        .cloned()
+        .collect();
+    newest.reverse();
+    newest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native;
+    use clap::FromArgMatches;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    const CONFLICT: &str = "taskchampion.sync-conflict-20240102-030405-ABCDEFG.sqlite3";
+
+    fn task(uuid: &str, description: &str, modified: &str) -> Task {
+        serde_json::from_value(json!({
+            "uuid": uuid,
+            "status": "pending",
+            "entry": "20240101T000000Z",
+            "modified": modified,
+            "description": description,
+        }))
+        .unwrap()
+    }
+
+    /// Write `tasks` into a database at `path`
+    fn write_db(path: &Path, tasks: &[Task]) {
+        let dir = TempDir::new().unwrap();
+        native::write(dir.path(), tasks).unwrap();
+        fs::copy(dir.path().join(MAIN_DB_NAME), path).unwrap();
+    }
+
+    fn args(flags: &[&str]) -> ResolveArgs {
+        let command = ResolveArgs::augment_args(clap::Command::new("test"));
+        let matches =
+            command.get_matches_from(std::iter::once("test").chain(flags.iter().copied()));
+        ResolveArgs::from_arg_matches(&matches).unwrap()
+    }
+
+    fn config(temp_dir: &Path) -> Config {
+        Config {
+            read_backend: Some(Backend::Native),
+            write_backend: Some(Backend::Native),
+            temp_dir: Some(temp_dir.to_owned()),
+            ..Config::default()
+        }
+    }
+
+    /// Every file below `dir` with its contents
+    fn contents(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        if !dir.exists() {
+            return files;
+        }
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            match path.is_dir() {
+                true => files.extend(contents(&path)),
+                false => {
+                    let data = fs::read(&path).unwrap();
+                    files.insert(path, data);
+                }
+            }
+        }
+        files
+    }
+
+    struct Dirs {
+        _root: TempDir,
+        task_dir: PathBuf,
+        state_dir: PathBuf,
+        temp_dir: PathBuf,
+    }
+
+    fn dirs() -> Dirs {
+        let root = TempDir::new().unwrap();
+        let task_dir = root.path().join("task");
+        let state_dir = root.path().join("state");
+        let temp_dir = root.path().join("tmp");
+        fs::create_dir(&task_dir).unwrap();
+        fs::create_dir(&temp_dir).unwrap();
+        Dirs {
+            _root: root,
+            task_dir,
+            state_dir,
+            temp_dir,
+        }
+    }
+
+    const UUID: &str = "11111111-1111-1111-1111-111111111111";
+
+    fn resolve_in(dirs: &Dirs, flags: &[&str]) -> Summary {
+        resolve(
+            Path::new("task"),
+            None,
+            &dirs.task_dir,
+            &dirs.state_dir,
+            &config(&dirs.temp_dir),
+            &args(flags),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn dry_run_leaves_no_backup_or_temp_dirs() {
+        let dirs = dirs();
+        write_db(
+            &dirs.task_dir.join(MAIN_DB_NAME),
+            &[task(UUID, "old", "20240102T000000Z")],
+        );
+        write_db(
+            &dirs.task_dir.join(CONFLICT),
+            &[task(UUID, "new", "20240103T000000Z")],
+        );
+        let before = contents(&dirs.task_dir);
+
+        let summary = resolve_in(&dirs, &["--dry-run"]);
+
+        assert!(!summary.applied);
+        assert_eq!(summary.num_conflicts, 1);
+        assert_eq!(summary.backup_dir, None);
+        assert_eq!(contents(&dirs.task_dir), before);
+        assert!(contents(&dirs.state_dir).is_empty());
+        assert_eq!(fs::read_dir(&dirs.temp_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn duplicated_db_is_a_no_op_conflict() {
+        let dirs = dirs();
+        let main_db = dirs.task_dir.join(MAIN_DB_NAME);
+        let conflict = dirs.task_dir.join(CONFLICT);
+        write_db(&main_db, &[task(UUID, "same", "20240102T000000Z")]);
+        fs::copy(&main_db, &conflict).unwrap();
+        let main_contents = fs::read(&main_db).unwrap();
+
+        let summary = resolve_in(&dirs, &["--dry-run"]);
+        assert_eq!(summary.num_conflicts, 0);
+        assert_eq!(summary.redundant, vec![conflict.clone()]);
+        assert!(conflict.is_file());
+
+        let summary = resolve_in(&dirs, &[]);
+        assert_eq!(summary.num_conflicts, 0);
+        assert_eq!(summary.redundant, vec![conflict.clone()]);
+        assert!(!conflict.exists());
+        assert_eq!(fs::read(&main_db).unwrap(), main_contents);
+        let backup_dir = summary.backup_dir.expect("the removed copy is backed up");
+        assert_eq!(fs::read(backup_dir.join(CONFLICT)).unwrap(), main_contents);
+    }
+}
@@ -0,0 +1,82 @@
+//! Holding off SIGINT and SIGTERM while the task dir is being changed.
+//!
+//! Outside of `defer`, the signals do what they always do. Inside it, they are only recorded, so the code changing the
+//! task dir can stop at a point where nothing is half done, or finish the little that is left.
+
+// TODO: register the flags with `signal_hook::flag::register` once signal-hook can be added as a dependency
+
+use color_eyre::{eyre::bail, Result};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+const SIGNALS: &[libc::c_int] = &[libc::SIGINT, libc::SIGTERM];
+
+/// Whether signals are currently being held off
+static DEFERRING: AtomicBool = AtomicBool::new(false);
+
+/// The last signal received while deferring, zero when there was none
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+/// Install the handler for SIGINT and SIGTERM. Until `defer` is called, it acts like the default handler
+pub fn install() {
+    for &signal in SIGNALS {
+        // SAFETY: the handler only touches atomics and calls functions that are async-signal-safe
+        unsafe {
+            libc::signal(
+                signal,
+                handle as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+extern "C" fn handle(signal: libc::c_int) {
+    if DEFERRING.load(Ordering::SeqCst) {
+        RECEIVED.store(signal, Ordering::SeqCst);
+    } else {
+        // SAFETY: signal and raise are async-signal-safe
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+}
+
+/// Hold off SIGINT and SIGTERM until the returned guard is dropped
+pub fn defer() -> Deferred {
+    DEFERRING.store(true, Ordering::SeqCst);
+    Deferred { _private: () }
+}
+
+/// Signals are held off for as long as this lives
+#[derive(Debug)]
+pub struct Deferred {
+    _private: (),
+}
+
+impl Deferred {
+    /// Fail if a signal came in, for the points where stopping leaves everything as it was. `next` names what would
+    /// have been done, e.g. "removing the conflict files"
+    pub fn check(&self, next: &str) -> Result<()> {
+        if let Some(name) = received() {
+            bail!("Received {}, stopping before {}", name, next);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Deferred {
+    fn drop(&mut self) {
+        DEFERRING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// The name of the signal received while deferring, if any. It stays set, so callers can stop once they are done with
+/// what can not be interrupted
+pub fn received() -> Option<&'static str> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        libc::SIGINT => Some("SIGINT"),
+        libc::SIGTERM => Some("SIGTERM"),
+        _ => Some("a signal"),
+    }
+}
@@ -0,0 +1,177 @@
+use crate::config::Config;
+use color_eyre::{eyre::bail, eyre::WrapErr, Result};
+use directories::BaseDirs;
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tracing::warn;
+
+/// A task dir to operate on, and where its backups are kept
+#[derive(Debug, Serialize)]
+pub struct Target {
+    pub task_dir: PathBuf,
+    pub state_dir: PathBuf,
+}
+
+impl Target {
+    pub fn new(task_dir: PathBuf, state_dir: PathBuf) -> Self {
+        Self {
+            task_dir,
+            state_dir,
+        }
+    }
+}
+
+/// The task dirs to operate on: `task_dir` (from --task-dir) if given, otherwise `task_dirs` or `task_dir` from the
+/// config, otherwise the one taskwarrior itself would use. With several task dirs configured, each gets its own
+/// subfolder of `state_dir` so backups don't collide
+pub fn targets(
+    task_dir: Option<PathBuf>,
+    config: &Config,
+    state_dir: &Path,
+) -> Result<Vec<Target>> {
+    let targets = match (task_dir, &config.task_dirs) {
+        (Some(dir), Some(dirs)) if dirs.contains(&dir) => {
+            let dir_state = state_dir.join(state_subdir_name(&dir));
+            vec![Target::new(dir, dir_state)]
+        }
+        (Some(dir), _) => vec![Target::new(dir, state_dir.to_path_buf())],
+        (None, Some(dirs)) => {
+            if config.task_dir.is_some() {
+                warn!("Both task_dir and task_dirs are set in the config, ignoring task_dir");
+            }
+            dirs.iter()
+                .map(|dir| Target::new(dir.clone(), state_dir.join(state_subdir_name(dir))))
+                .collect()
+        }
+        (None, None) => {
+            let dir = match &config.task_dir {
+                Some(dir) => dir.clone(),
+                None => default_task_dir()?,
+            };
+            vec![Target::new(dir, state_dir.to_path_buf())]
+        }
+    };
+    Ok(targets)
+}
+
+/// Name of the state subfolder for backups of `task_dir`, derived from its path (e.g. `home_me_.local_share_task`)
+pub fn state_subdir_name(task_dir: &Path) -> String {
+    let name: Vec<String> = task_dir
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    name.join("_")
+}
+
+/// The data dir taskwarrior itself would use: `$TASKDATA` if set, otherwise `${XDG_DATA_HOME}/task`, falling back to
+/// `~/.local/share/task` when XDG_DATA_HOME is unset
+pub fn default_task_dir() -> Result<PathBuf> {
+    let task_dir = match env::var_os("TASKDATA") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let Some(base_dirs) = BaseDirs::new() else {
+                bail!("Unable to find the home directory to locate the taskwarrior data dir, set it with --task-dir");
+            };
+            base_dirs.data_dir().join("task")
+        }
+    };
+
+    // Resolve symlinks so the state subfolder and log messages name the real directory
+    match fs::canonicalize(&task_dir) {
+        Ok(task_dir) => Ok(task_dir),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(task_dir),
+        Err(e) => Err(e).wrap_err_with(|| format!("Unable to resolve {}", task_dir.display())),
+    }
+}
+
+/// Fail with a hint on where the task dir comes from when it is missing or not a directory, as after a typo in
+/// --task-dir, rather than with whatever reading it would fail with
+pub fn check_task_dir(task_dir: &Path) -> Result<()> {
+    if !task_dir.is_dir() {
+        bail!(
+            "Task data directory {} does not exist or is not a directory. Pass the right one with --task-dir, or set \
+             task_dir in the config",
+            task_dir.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// The environment is shared by every test thread
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// `default_task_dir` with XDG_DATA_HOME set to `data_home` (unset for None) and HOME set to `home`
+    fn task_dir_with(data_home: Option<&Path>, home: &Path) -> PathBuf {
+        let _env = ENV.lock().unwrap();
+        env::remove_var("TASKDATA");
+        env::set_var("HOME", home);
+        match data_home {
+            Some(dir) => env::set_var("XDG_DATA_HOME", dir),
+            None => env::remove_var("XDG_DATA_HOME"),
+        }
+        default_task_dir().unwrap()
+    }
+
+    #[test]
+    fn xdg_data_home_with_a_trailing_slash() {
+        let home = tempfile::tempdir().unwrap();
+        let data_home = home.path().join("data");
+        fs::create_dir_all(data_home.join("task")).unwrap();
+
+        let mut with_slash = data_home.clone().into_os_string();
+        with_slash.push("/");
+        let task_dir = task_dir_with(Some(Path::new(&with_slash)), home.path());
+        assert_eq!(task_dir, fs::canonicalize(data_home.join("task")).unwrap());
+    }
+
+    #[test]
+    fn xdg_data_home_unset_falls_back_to_local_share() {
+        let home = tempfile::tempdir().unwrap();
+        let task_dir = task_dir_with(None, home.path());
+        // Missing, so it is taken as it is
+        assert_eq!(task_dir, home.path().join(".local/share/task"));
+    }
+
+    #[test]
+    fn xdg_data_home_through_a_symlink() {
+        let home = tempfile::tempdir().unwrap();
+        let real = home.path().join("real");
+        fs::create_dir_all(real.join("task")).unwrap();
+        let link = home.path().join("link");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let task_dir = task_dir_with(Some(&link), home.path());
+        assert_eq!(task_dir, fs::canonicalize(real.join("task")).unwrap());
+    }
+
+    #[test]
+    fn each_of_several_task_dirs_gets_a_state_subdir() {
+        let config = Config {
+            task_dirs: Some(vec![
+                PathBuf::from("/home/me/task"),
+                PathBuf::from("/srv/task"),
+            ]),
+            ..Config::default()
+        };
+        let targets = targets(None, &config, Path::new("/state")).unwrap();
+        let state_dirs: Vec<&Path> = targets.iter().map(|t| t.state_dir.as_path()).collect();
+        assert_eq!(
+            state_dirs,
+            [
+                Path::new("/state/home_me_task"),
+                Path::new("/state/srv_task")
+            ]
+        );
+    }
+}
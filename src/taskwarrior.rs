@@ -0,0 +1,478 @@
+use crate::config::Config;
+use crate::native::{COMPUTED_FIELDS, DATE_FIELDS};
+use color_eyre::{eyre::bail, eyre::WrapErr, Result};
+use jiff::fmt::strtime;
+use jiff::Timestamp;
+use serde_json::Value;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use task_hookrs::task::Task;
+use task_hookrs::tw;
+use taskchampion::StorageConfig;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// How taskwarrior writes dates in its JSON format
+const DATE_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Build a `task` invocation that reads and writes the database in `data_dir`.
+/// TASKDATA is set on the child only, so concurrent invocations can target different directories.
+/// The user's hooks are turned off: they have no business changing the scratch databases, and an `on-launch` hook
+/// running this tool would otherwise start it again for every database. Quiet, so the override isn't reported on stderr
+fn command(task_bin: &Path, data_dir: &Path) -> Command {
+    let mut cmd = Command::new(task_bin);
+    cmd.env("TASKDATA", data_dir);
+    cmd.args(["rc.hooks=0", "rc.verbose=nothing"]);
+    cmd
+}
+
+/// Export all tasks matching `query` (taskwarrior filter syntax) from the database in `data_dir`
+pub fn query(task_bin: &Path, data_dir: &Path, query: &str) -> Result<Vec<Task>> {
+    let mut cmd = tw::add_query_to_cmd(query, command(task_bin, data_dir));
+    cmd.stdout(Stdio::piped());
+    Ok(tw::run_query_cmd(cmd)?)
+}
+
+/// Every task in the database in `data_dir`, exactly as `task export` prints it
+pub fn export(task_bin: &Path, data_dir: &Path) -> Result<Vec<Value>> {
+    let output = command(task_bin, data_dir)
+        .arg("export")
+        .output()
+        .wrap_err_with(|| format!("Unable to run '{}'", task_bin.display()))?;
+    if !output.status.success() {
+        bail!(
+            "'{} export' failed: {}",
+            task_bin.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout).wrap_err_with(|| {
+        format!(
+            "Unable to parse the output of '{} export'",
+            task_bin.display()
+        )
+    })
+}
+
+/// What `task _get` prints for `reference` (e.g. `rc.context`), which is empty when it isn't set
+pub fn get(task_bin: &Path, data_dir: &Path, reference: &str) -> Result<String> {
+    let output = command(task_bin, data_dir)
+        .args(["_get", reference])
+        .output()
+        .wrap_err_with(|| format!("Unable to run '{}'", task_bin.display()))?;
+    if !output.status.success() {
+        bail!(
+            "'{} _get {}' failed: {}",
+            task_bin.display(),
+            reference,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// The read filter of the context taskwarrior has active (set with `task context <name>`), or None when there is no
+/// active context or it doesn't filter what is shown
+pub fn context_filter(task_bin: &Path, data_dir: &Path) -> Result<Option<String>> {
+    let context = get(task_bin, data_dir, "rc.context")?;
+    if context.is_empty() {
+        info!("No taskwarrior context is active, merging all tasks");
+        return Ok(None);
+    }
+    let filter = get(task_bin, data_dir, &format!("rc.context.{}.read", context))?;
+    if filter.is_empty() {
+        info!(
+            "Taskwarrior context '{}' has no read filter, merging all tasks",
+            context
+        );
+        return Ok(None);
+    }
+    info!(
+        "Using the read filter of taskwarrior context '{}': {}",
+        context, filter
+    );
+    Ok(Some(filter))
+}
+
+/// The version `task --version` reports, e.g. `3.1.0`
+pub fn version(task_bin: &Path) -> Result<String> {
+    let output = Command::new(task_bin)
+        .arg("--version")
+        .output()
+        .wrap_err_with(|| format!("Unable to run '{}'", task_bin.display()))?;
+    if !output.status.success() {
+        bail!(
+            "'{} --version' failed ({})",
+            task_bin.display(),
+            output.status
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Oldest taskwarrior version whose database and JSON format the merge is known to work with. 3.0 is the first to
+/// store tasks in a taskchampion database
+const MIN_VERSION: (u64, u64, u64) = (3, 0, 0);
+
+/// First major version that may change the format again
+const MAX_MAJOR_VERSION: u64 = 4;
+
+/// The major, minor, and patch numbers of a version reported by `task --version`, ignoring any suffix like `-dev`
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let mut numbers = version
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()?
+        .split('.')
+        .map(|n| n.parse().ok());
+    let major = numbers.next()??;
+    let minor = numbers.next().flatten().unwrap_or(0);
+    let patch = numbers.next().flatten().unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+/// Get the version of `task_bin`, and warn when it is not one the merge is known to work with, or can't be found out
+/// at all. With `strict`, that is an error instead
+pub fn check_version(task_bin: &Path, strict: bool) -> Result<Option<String>> {
+    let version = match version(task_bin) {
+        Ok(version) => version,
+        Err(e) if !strict => {
+            warn!(
+                "{:#}, unable to tell whether taskwarrior is a version known to work",
+                e
+            );
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    info!("taskwarrior version: {}", version);
+
+    let problem = match parse_version(&version) {
+        Some(parsed) if parsed >= MIN_VERSION && parsed.0 < MAX_MAJOR_VERSION => return Ok(Some(version)),
+        Some(_) => format!(
+            "taskwarrior {} is outside the versions known to work ({}.{}.{} and up, before {}.0.0), its import and export \
+             format may not match what the merge expects",
+            version, MIN_VERSION.0, MIN_VERSION.1, MIN_VERSION.2, MAX_MAJOR_VERSION
+        ),
+        None => format!("Unable to make sense of taskwarrior version '{}'", version),
+    };
+    if strict {
+        bail!("{}", problem);
+    }
+    warn!("{}", problem);
+    Ok(Some(version))
+}
+
+/// Import `tasks` into the database in `data_dir`, creating it if needed
+pub fn import(task_bin: &Path, data_dir: &Path, tasks: &[Task]) -> Result<()> {
+    let Ok(mut child) = command(task_bin, data_dir)
+        .args(["import"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    else {
+        bail!("Unable to run '{}'", task_bin.display());
+    };
+
+    // Pass tasks as a json array to stdin, serializing one task at a time so the whole array is never in memory.
+    // Dropping stdin at the end of the thread closes it, so task sees the end of input
+    let stdin = child.stdin.take().expect("Failed to open stdin");
+    let (output, written) = thread::scope(|scope| {
+        let writer = scope.spawn(move || write_json_array(BufWriter::new(stdin), tasks));
+        let output = child.wait_with_output();
+        (output, writer.join().expect("stdin writer thread panicked"))
+    });
+
+    let output = output
+        .wrap_err_with(|| format!("Unable to read output of '{} import'", task_bin.display()))?;
+    let stdout = &output.stdout;
+    if !stdout.is_empty() {
+        let stdout = String::from_utf8_lossy(stdout);
+        debug!("task stdout: {}", stdout);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    if !output.status.success() {
+        bail!(
+            "'{} import' failed ({}): {}",
+            task_bin.display(),
+            output.status,
+            stderr.trim()
+        );
+    }
+    if !stderr.is_empty() {
+        warn!("task stderr: {}", stderr);
+    }
+
+    // A write error means task stopped reading early, which should have shown up in the exit status, but don't trust
+    // a partial import either way
+    written.wrap_err_with(|| format!("Unable to pass tasks to '{} import'", task_bin.display()))?;
+
+    Ok(())
+}
+
+/// Write `tasks` to `out` as a json array, one element at a time
+fn write_json_array(mut out: impl Write, tasks: &[Task]) -> Result<()> {
+    out.write_all(b"[")?;
+    for (i, task) in tasks.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",\n")?;
+        }
+        serde_json::to_writer(&mut out, &normalize(task)?)?;
+    }
+    out.write_all(b"]\n")?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Write `tasks` to `out` as one json object per line, which `task import` reads as well as an array
+pub fn write_ndjson(mut out: impl Write, tasks: &[Task]) -> Result<()> {
+    for task in tasks {
+        serde_json::to_writer(&mut out, &normalize(task)?)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// The task as `task import` accepts it: without the attributes `task export` computes, which import rejects, and
+/// with every date in taskwarrior's `20240102T030405Z` form
+fn normalize(task: &Task) -> Result<Value> {
+    normalize_json(task.uuid(), serde_json::to_value(task)?)
+}
+
+/// `normalize` on the task `uuid` as JSON, which may have dates in forms task-hookrs would not read
+fn normalize_json(uuid: &Uuid, task: Value) -> Result<Value> {
+    let Value::Object(mut fields) = task else {
+        bail!("Task {} did not serialize to a JSON object", uuid);
+    };
+    for field in COMPUTED_FIELDS {
+        fields.remove(*field);
+    }
+    for (key, value) in fields.iter_mut() {
+        if DATE_FIELDS.contains(&key.as_str()) {
+            *value = import_date(uuid, key, value)?;
+        }
+    }
+    if let Some(Value::Array(annotations)) = fields.get_mut("annotations") {
+        for annotation in annotations {
+            if let Some(entry) = annotation.get_mut("entry") {
+                *entry = import_date(uuid, "annotations", entry)?;
+            }
+        }
+    }
+    Ok(Value::Object(fields))
+}
+
+/// A date attribute as `task import` expects it. task-hookrs already writes that form, others such as RFC 3339
+/// (`2024-01-02T03:04:05Z`) are converted to it
+fn import_date(uuid: &Uuid, key: &str, value: &Value) -> Result<Value> {
+    let Value::String(date) = value else {
+        bail!("Task {} has an unexpected value {} in {}", uuid, value, key);
+    };
+    if strtime::parse(DATE_FORMAT, date).is_ok() {
+        return Ok(value.clone());
+    }
+    let timestamp: Timestamp = date
+        .parse()
+        .wrap_err_with(|| format!("Task {} has an invalid date '{}' in {}", uuid, date, key))?;
+    Ok(Value::from(timestamp.strftime(DATE_FORMAT).to_string()))
+}
+
+/// Fold the write-ahead log next to the database in `data_dir` back into the database file.
+/// taskchampion keeps its database in WAL mode, so recent changes may only be in `taskchampion.sqlite3-wal` until
+/// the last connection closes. Opening and closing the database ourselves performs that checkpoint.
+pub fn checkpoint(data_dir: &Path) -> Result<()> {
+    let storage = StorageConfig::OnDisk {
+        taskdb_dir: data_dir.to_owned(),
+        create_if_missing: false,
+    }
+    .into_storage()
+    .wrap_err_with(|| format!("Unable to open the database in {}", data_dir.display()))?;
+    drop(storage);
+    Ok(())
+}
+
+/// The taskwarrior binary to run, from --task-bin, the config, or the $PATH. Not finding it is only an error when it is
+/// `needed`: with both backends native, resolving reads and writes the databases without it, so it is only warned about
+pub fn task_bin(arg: Option<&Path>, config: &Config, needed: bool) -> Result<PathBuf> {
+    let task_bin = arg.or(config.task_bin.as_deref());
+    match find_task_bin(task_bin) {
+        Ok(path) => Ok(path),
+        Err(e) if needed => Err(e),
+        Err(e) => {
+            warn!(
+                "{:#}. Continuing, the native backends read and write the databases without it",
+                e
+            );
+            Ok(task_bin.map_or_else(|| PathBuf::from("task"), Path::to_path_buf))
+        }
+    }
+}
+
+/// The taskwarrior binary at `path`, or the one on the $PATH when not given
+pub fn find_task_bin(path: Option<&Path>) -> Result<PathBuf> {
+    match path {
+        // which checks that a path is an executable file, rather than searching for it
+        Some(path) => which::which(path).wrap_err_with(|| {
+            format!(
+                "Taskwarrior binary {} does not exist or is not executable",
+                path.display()
+            )
+        }),
+        None => which::which("task").wrap_err(
+            "Unable to find taskwarrior binary ('task') on the $PATH, set it with --task-bin",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const UUID: &str = "11111111-1111-1111-1111-111111111111";
+
+    #[test]
+    fn normalize_drops_computed_fields_and_keeps_the_rest() {
+        let task: Task = serde_json::from_value(json!({
+            "id": 3,
+            "urgency": 4.2,
+            "uuid": UUID,
+            "status": "pending",
+            "entry": "20240101T000000Z",
+            "modified": "20240102T030405Z",
+            "due": "20240201T120000Z",
+            "description": "a task",
+            "annotations": [{"entry": "20240101T120000Z", "description": "a note"}],
+            "tags": ["a"],
+            "estimate": "2h",
+        }))
+        .unwrap();
+        assert_eq!(
+            normalize(&task).unwrap(),
+            json!({
+                "uuid": UUID,
+                "status": "pending",
+                "entry": "20240101T000000Z",
+                "modified": "20240102T030405Z",
+                "due": "20240201T120000Z",
+                "description": "a task",
+                "annotations": [{"entry": "20240101T120000Z", "description": "a note"}],
+                "tags": ["a"],
+                "estimate": "2h",
+            })
+        );
+    }
+
+    #[test]
+    fn normalize_converts_rfc_3339_dates() {
+        let uuid: Uuid = UUID.parse().unwrap();
+        let task = json!({
+            "id": 3,
+            "urgency": 4.2,
+            "uuid": UUID,
+            "status": "pending",
+            "entry": "2024-01-01T00:00:00Z",
+            "modified": "2024-01-02T05:04:05+02:00",
+            "description": "a task",
+            "annotations": [{"entry": "2024-01-01T12:00:00.5Z", "description": "a note"}],
+        });
+        assert_eq!(
+            normalize_json(&uuid, task).unwrap(),
+            json!({
+                "uuid": UUID,
+                "status": "pending",
+                "entry": "20240101T000000Z",
+                "modified": "20240102T030405Z",
+                "description": "a task",
+                "annotations": [{"entry": "20240101T120000Z", "description": "a note"}],
+            })
+        );
+    }
+
+    #[test]
+    fn normalize_rejects_invalid_dates() {
+        let uuid: Uuid = UUID.parse().unwrap();
+        let task = json!({"uuid": UUID, "entry": "yesterday"});
+        assert!(normalize_json(&uuid, task).is_err());
+    }
+
+    #[test]
+    fn ndjson_has_one_task_per_line() {
+        let task: Task = serde_json::from_value(json!({
+            "uuid": UUID,
+            "status": "pending",
+            "entry": "20240101T000000Z",
+            "description": "a task",
+            "urgency": 1.0,
+        }))
+        .unwrap();
+        let mut out = Vec::new();
+        write_ndjson(&mut out, &[task.clone(), task]).unwrap();
+        let line = format!(
+            r#"{{"description":"a task","entry":"20240101T000000Z","status":"pending","uuid":"{}"}}"#,
+            UUID
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{0}\n{0}\n", line));
+    }
+
+    #[test]
+    fn tasks_survive_a_round_trip_through_import() {
+        const DEPENDENCY: &str = "22222222-2222-2222-2222-222222222222";
+        let written: Vec<Task> = [
+            json!({
+                "uuid": UUID,
+                "status": "pending",
+                "entry": "20240101T000000Z",
+                "modified": "20240102T030405Z",
+                "due": "20240201T120000Z",
+                "wait": "20240115T080000Z",
+                "description": "a task",
+                "project": "home",
+                "priority": "H",
+                "tags": ["a", "b"],
+                "annotations": [
+                    {"entry": "20240101T120000Z", "description": "a note"},
+                    {"entry": "20240101T130000Z", "description": "another note"},
+                ],
+                "depends": [DEPENDENCY],
+                "estimate": "2h",
+            }),
+            json!({
+                "uuid": DEPENDENCY,
+                "status": "completed",
+                "entry": "20231201T080000Z",
+                "modified": "20231202T090000Z",
+                "end": "20231202T090000Z",
+                "description": "done already",
+            }),
+        ]
+        .into_iter()
+        .map(|task| serde_json::from_value(task).unwrap())
+        .collect();
+
+        let exported_dir = tempfile::tempdir().unwrap();
+        crate::native::write(exported_dir.path(), &written).unwrap();
+        let exported = crate::native::read(exported_dir.path()).unwrap();
+
+        // What `import` hands to `task import`, read back the way taskwarrior would
+        let imported: Vec<Task> = exported
+            .iter()
+            .map(|task| serde_json::from_value(normalize(task).unwrap()).unwrap())
+            .collect();
+        let imported_dir = tempfile::tempdir().unwrap();
+        crate::native::write(imported_dir.path(), &imported).unwrap();
+        let reimported = crate::native::read(imported_dir.path()).unwrap();
+
+        let as_json = |tasks: &[Task]| {
+            let mut tasks: Vec<Value> = tasks.iter().map(|task| normalize(task).unwrap()).collect();
+            tasks.sort_by_key(|task| task["uuid"].to_string());
+            tasks
+        };
+        assert_eq!(as_json(&reimported), as_json(&exported));
+        assert_eq!(as_json(&exported), as_json(&written));
+    }
+}
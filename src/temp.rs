@@ -0,0 +1,77 @@
+use crate::config::Config;
+use color_eyre::{eyre::WrapErr, Result};
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+use tracing::{debug, info};
+
+/// Start of the name of a run's temporary directory, followed by the process ID
+const TEMP_DIR_PREFIX: &str = "syncthing-task-resolve-";
+
+/// Create a directory for all the scratch dirs of this run in `temp_dir` (or `$TMPDIR`), and point `temp_dir` at it.
+/// It is removed when the returned value is dropped, and otherwise, as after a SIGKILL, has the process ID in its name
+/// for `cleanup` to tell that it was left behind
+pub fn run_dir(config: &mut Config) -> Result<tempfile::TempDir> {
+    let temp_dir = config.temp_dir.clone().unwrap_or_else(env::temp_dir);
+    let run_temp = tempfile::Builder::new()
+        .prefix(&format!("{}{}-", TEMP_DIR_PREFIX, process::id()))
+        .tempdir_in(&temp_dir)
+        .wrap_err_with(|| {
+            format!(
+                "Unable to create a temporary directory in {}, set temp_dir in the config to somewhere writable",
+                temp_dir.display()
+            )
+        })?;
+    debug!("Keeping scratch copies in {}", run_temp.path().display());
+    config.temp_dir = Some(run_temp.path().to_owned());
+    Ok(run_temp)
+}
+
+/// Remove the temporary directories of runs that are no longer running from `temp_dir` (or `$TMPDIR`)
+pub fn cleanup(config: &Config) -> Result<()> {
+    let temp_dir = config.temp_dir.clone().unwrap_or_else(env::temp_dir);
+    let entries = fs::read_dir(&temp_dir)
+        .wrap_err_with(|| format!("Unable to read {}", temp_dir.display()))?;
+    let mut removed = 0;
+    for entry in entries {
+        let path = entry?.path();
+        let Some(pid) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(TEMP_DIR_PREFIX))
+            .and_then(|rest| rest.split_once('-'))
+            .and_then(|(pid, _)| pid.parse::<libc::pid_t>().ok())
+        else {
+            continue;
+        };
+        if !path.is_dir() {
+            continue;
+        }
+        if is_running(pid) {
+            info!(
+                "Leaving {}, process {} is still running",
+                path.display(),
+                pid
+            );
+            continue;
+        }
+        fs::remove_dir_all(&path)
+            .wrap_err_with(|| format!("Unable to remove {}", path.display()))?;
+        info!("Removed {}", path.display());
+        removed += 1;
+    }
+    println!(
+        "Removed {} leftover temporary directories from {}",
+        removed,
+        temp_dir.display()
+    );
+    Ok(())
+}
+
+/// Whether a process with this ID exists. One that can't be signalled (e.g. of another user) still counts
+fn is_running(pid: libc::pid_t) -> bool {
+    // SAFETY: signal 0 only checks whether the process exists, nothing is sent
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
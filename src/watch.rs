@@ -0,0 +1,128 @@
+//! Watching task dirs for new conflict files, and resolving them once they have settled. The task dirs are polled
+//! every `POLL_INTERVAL`.
+
+// TODO: watch for events with notify's `recommended_watcher`, keeping its `PollWatcher` as the fallback, once notify
+// can be added as a dependency
+
+use crate::config::Config;
+use crate::legacy;
+use crate::run::{self, ResolveArgs};
+use crate::signal;
+use crate::target::Target;
+use color_eyre::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, info, warn};
+
+/// How often `watch` looks for new conflict files
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Resolve `targets` now, and then again whenever their conflict files change and have stayed the same for `debounce`.
+/// Only returns on errors outside of resolving, a failed resolve is logged and watching goes on
+pub fn watch(
+    task_bin: &Path,
+    task_version: Option<&str>,
+    targets: &[Target],
+    config: &Config,
+    args: &ResolveArgs,
+    debounce: Duration,
+) -> Result<ExitCode> {
+    let resolve = || {
+        if let Err(e) = run::resolve_targets(task_bin, task_version, targets, config, args) {
+            // Being told to stop is not something to carry on from
+            if signal::received().is_some() {
+                return Err(e);
+            }
+            error!("Resolving failed: {:#}", e);
+        }
+        Ok(conflict_files(targets, config))
+    };
+
+    info!("Resolving the conflicts there already are");
+    let mut after_resolve = resolve()?;
+    // Conflict files as last seen, and since when they have looked like that
+    let mut settling: Option<(Vec<FileState>, Instant)> = None;
+    info!("Watching {} task dirs for conflict files", targets.len());
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let current = conflict_files(targets, config);
+        // Whatever the last resolve left behind (e.g. files it skipped) is only looked at again once it changes
+        if current.is_empty() || current == after_resolve {
+            settling = None;
+            continue;
+        }
+        match &settling {
+            Some((seen, since)) if *seen == current => {
+                if since.elapsed() >= debounce {
+                    info!("{} conflict files settled, resolving", current.len());
+                    after_resolve = resolve()?;
+                    settling = None;
+                }
+            }
+            _ => {
+                debug!("Conflict files changed, waiting for them to settle");
+                settling = Some((current, Instant::now()));
+            }
+        }
+    }
+}
+
+/// A conflict file, and what it looked like when it was last checked
+type FileState = (PathBuf, u64, Option<SystemTime>);
+
+/// Every conflict file in `targets`, sorted by path. A task dir that can't be read is logged and taken as having none
+fn conflict_files(targets: &[Target], config: &Config) -> Vec<FileState> {
+    let mut files = Vec::new();
+    for target in targets {
+        let mut paths = match crate::detect_conflicts(&target.task_dir, config) {
+            Ok(conflicts) => conflicts.into_iter().map(|c| c.path).collect(),
+            Err(e) => {
+                warn!("{:#}", e);
+                Vec::new()
+            }
+        };
+        if config.legacy_format.unwrap_or(false) {
+            match legacy::detect_conflicts(&target.task_dir, config.syncthing_date_format()) {
+                Ok(conflicts) => paths.extend(conflicts.into_iter().map(|c| c.path)),
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+        for path in paths {
+            let metadata = fs::metadata(&path).ok();
+            let len = metadata.as_ref().map_or(0, |metadata| metadata.len());
+            let modified = metadata.and_then(|metadata| metadata.modified().ok());
+            files.push((path, len, modified));
+        }
+    }
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFLICT_NAME: &str = "taskchampion.sync-conflict-20240103-101010-ABCDEFG.sqlite3";
+
+    #[test]
+    fn polling_sees_a_conflict_file_grow() {
+        let dir = tempfile::tempdir().unwrap();
+        let targets = [Target::new(dir.path().to_owned(), dir.path().join("state"))];
+        let config = Config::default();
+        assert!(conflict_files(&targets, &config).is_empty());
+
+        let path = dir.path().join(CONFLICT_NAME);
+        fs::write(&path, b"partial").unwrap();
+        fs::write(dir.path().join("unrelated"), b"").unwrap();
+        let seen = conflict_files(&targets, &config);
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].0, path);
+        assert_eq!(seen[0].1, 7);
+
+        fs::write(&path, b"partial, and then some").unwrap();
+        assert_ne!(conflict_files(&targets, &config), seen);
+    }
+}